@@ -0,0 +1,125 @@
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+
+use chrono::{TimeZone, Utc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+use crate::vad_processor::TARGET_SAMPLE_RATE;
+
+/// Where the offsets of a transcript can be found in the archived speech clips.
+pub struct TranscriptAudio {
+    // relative to the archive dir
+    pub path: String,
+    // milliseconds relative to the start of the clip
+    pub start_ms: i64,
+    pub end_ms: i64,
+}
+
+fn wav_spec() -> hound::WavSpec {
+    hound::WavSpec {
+        channels: 1,
+        sample_rate: TARGET_SAMPLE_RATE as u32,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    }
+}
+
+fn encode_wav(samples: &[i16]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut cursor = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut cursor, wav_spec())?;
+    for sample in samples {
+        writer.write_sample(*sample)?;
+    }
+    writer.finalize()?;
+    Ok(cursor.into_inner())
+}
+
+/// Speech clips cut by vad, saved as 16 kHz mono wav files under
+/// `<dir>/<show_name>/<yyyy-mm-dd>/<start millis>.wav`.
+pub struct AudioArchive {
+    dir: PathBuf,
+}
+
+impl AudioArchive {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the path of the saved clip relative to the archive dir.
+    pub fn save_clip(&self, show_name: &str, start_timestamp_millis: i64, samples: &[i16]) -> Result<String, Box<dyn std::error::Error>> {
+        let start = Utc.timestamp_millis_opt(start_timestamp_millis).single().ok_or("invalid clip timestamp")?;
+        let relative = Path::new(show_name)
+            .join(start.format("%Y-%m-%d").to_string())
+            .join(format!("{}.wav", start_timestamp_millis));
+        let path = self.dir.join(&relative);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, encode_wav(samples)?)?;
+        Ok(relative.to_string_lossy().replace('\\', "/"))
+    }
+
+    // only paths inside the archive dir
+    fn resolve(&self, relative: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let relative = Path::new(relative);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(format!("invalid clip path {}", relative.display()).into());
+        }
+        Ok(self.dir.join(relative))
+    }
+
+    /// Cut `start_ms..end_ms` of a clip, widened by `padding_ms` on both sides as far as
+    /// the clip goes, and return it as a wav file.
+    pub fn read_clip(&self, audio: &TranscriptAudio, padding_ms: i64) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut reader = hound::WavReader::open(self.resolve(&audio.path)?)?;
+        let spec = reader.spec();
+        if spec.channels != 1 || spec.bits_per_sample != 16 || spec.sample_format != hound::SampleFormat::Int {
+            return Err(format!("unexpected format in {}", audio.path).into());
+        }
+        let sample_rate = spec.sample_rate as i64;
+        let total = reader.duration() as i64;
+        let start = ((audio.start_ms - padding_ms).max(0) * sample_rate / 1000).min(total);
+        let end = ((audio.end_ms + padding_ms) * sample_rate / 1000).clamp(start, total);
+
+        reader.seek(start as u32)?;
+        let samples = reader.samples::<i16>()
+            .take((end - start) as usize)
+            .collect::<Result<Vec<i16>, _>>()?;
+        encode_wav(&samples)
+    }
+}
+
+/// Re-encode a wav file to opus in an ogg container with ffmpeg.
+pub async fn wav_to_opus(wav: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut child = Command::new("ffmpeg")
+        .args([
+            "-hide_banner",
+            "-loglevel", "error",
+            "-f", "wav",
+            "-i", "pipe:0",
+            "-c:a", "libopus",
+            "-b:a", "32k",
+            "-f", "ogg",
+            "pipe:1",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().ok_or("failed to capture ffmpeg stdin")?;
+    let mut stdout = child.stdout.take().ok_or("failed to capture ffmpeg stdout")?;
+
+    // write while reading so neither pipe fills up
+    let writer = tokio::spawn(async move {
+        stdin.write_all(&wav).await
+    });
+    let mut opus = Vec::new();
+    stdout.read_to_end(&mut opus).await?;
+    writer.await??;
+
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(format!("ffmpeg failed with a non-zero exit code {}", status.code().unwrap_or(-1)).into());
+    }
+    Ok(opus)
+}
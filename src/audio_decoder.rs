@@ -0,0 +1,266 @@
+use std::process::Stdio;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+use dasp_sample::Sample;
+use log::{debug, error};
+use samplerate::{convert, ConverterType, Samplerate};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::{ChildStdin, Command};
+
+use crate::vad_processor::{SAMPLE_SIZE, TARGET_SAMPLE_RATE};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SampleEncoding {
+    S16le,
+    F32le,
+}
+
+impl SampleEncoding {
+    pub fn parse(value: &str) -> Result<SampleEncoding, Box<dyn std::error::Error>> {
+        match value.trim().to_lowercase().as_str() {
+            "s16le" => Ok(SampleEncoding::S16le),
+            "f32le" => Ok(SampleEncoding::F32le),
+            _ => Err(format!("unsupported sample encoding {}, expected s16le or f32le", value).into()),
+        }
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleEncoding::S16le => 2,
+            SampleEncoding::F32le => 4,
+        }
+    }
+}
+
+/// Raw pcm as declared by the client, interleaved when there is more than one channel.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub encoding: SampleEncoding,
+}
+
+impl Default for PcmFormat {
+    // what the web page sends with AudioContext({sampleRate: 16000})
+    fn default() -> Self {
+        Self {
+            sample_rate: TARGET_SAMPLE_RATE as u32,
+            channels: 1,
+            encoding: SampleEncoding::S16le,
+        }
+    }
+}
+
+impl PcmFormat {
+    pub fn new(sample_rate: u32, channels: u16, encoding: SampleEncoding) -> Result<PcmFormat, Box<dyn std::error::Error>> {
+        if !(8000..=192000).contains(&sample_rate) {
+            return Err(format!("sample rate {} out of range 8000 to 192000", sample_rate).into());
+        }
+        if !(1..=8).contains(&channels) {
+            return Err(format!("channels {} out of range 1 to 8", channels).into());
+        }
+        Ok(Self { sample_rate, channels, encoding })
+    }
+
+    /// Already 16 kHz mono s16le, can be passed through as is.
+    pub fn is_native(&self) -> bool {
+        *self == PcmFormat::default()
+    }
+
+    pub fn bytes_per_frame(&self) -> usize {
+        self.encoding.bytes_per_sample() * self.channels as usize
+    }
+
+    /// Downmix and resample to 16 kHz mono, `bytes` must hold whole frames.
+    pub fn to_target_samples(self, bytes: &[u8]) -> Result<Vec<i16>, Box<dyn std::error::Error>> {
+        let mono = self.to_mono(bytes);
+        let resampled = if self.sample_rate != TARGET_SAMPLE_RATE as u32 {
+            convert(self.sample_rate, TARGET_SAMPLE_RATE as u32, 1, ConverterType::SincMediumQuality, &mono)?
+        } else {
+            mono
+        };
+        Ok(to_i16(&resampled))
+    }
+
+    fn to_mono(self, bytes: &[u8]) -> Vec<f32> {
+        let channels = self.channels as usize;
+        let bytes_per_sample = self.encoding.bytes_per_sample();
+        bytes.chunks_exact(self.bytes_per_frame()).map(|frame| {
+            let sum: f32 = frame.chunks_exact(bytes_per_sample).map(|sample| match self.encoding {
+                SampleEncoding::S16le => i16::from_le_bytes([sample[0], sample[1]]).to_sample::<f32>(),
+                SampleEncoding::F32le => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+            }).sum();
+            sum / channels as f32
+        }).collect()
+    }
+}
+
+fn to_i16(samples: &[f32]) -> Vec<i16> {
+    samples.iter().map(|&x| x.clamp(-1.0, 1.0).to_sample::<i16>()).collect()
+}
+
+/// Converts the raw pcm of one recording session to 16 kHz mono s16le.
+///
+/// The resampler keeps its filter state and any partial frame between chunks, so the
+/// chunks of a session resample the same as one continuous recording.
+pub struct PcmResampler {
+    pub format: PcmFormat,
+    resampler: Option<Samplerate>,
+    partial_frame: Vec<u8>,
+}
+
+impl PcmResampler {
+    pub fn new(format: PcmFormat) -> Result<Self, Box<dyn std::error::Error>> {
+        let resampler = if format.sample_rate != TARGET_SAMPLE_RATE as u32 {
+            Some(Samplerate::new(ConverterType::SincMediumQuality, format.sample_rate, TARGET_SAMPLE_RATE as u32, 1)?)
+        } else {
+            None
+        };
+        Ok(Self { format, resampler, partial_frame: Vec::new() })
+    }
+
+    /// Converts the whole frames of `bytes`, keeping a trailing partial frame for the next call.
+    pub fn process(&mut self, bytes: &[u8]) -> Result<Vec<i16>, Box<dyn std::error::Error>> {
+        self.partial_frame.extend_from_slice(bytes);
+        let whole_frames = self.partial_frame.len() - self.partial_frame.len() % self.format.bytes_per_frame();
+        let mono = self.format.to_mono(&self.partial_frame[..whole_frames]);
+        self.partial_frame.drain(..whole_frames);
+        let resampled = match &self.resampler {
+            Some(resampler) => resampler.process(&mono)?,
+            None => mono,
+        };
+        Ok(to_i16(&resampled))
+    }
+
+    /// Flushes the samples still held by the resampler at the end of the session.
+    pub fn finish(self) -> Result<Vec<i16>, Box<dyn std::error::Error>> {
+        match &self.resampler {
+            Some(resampler) => Ok(to_i16(&resampler.process_last(&[])?)),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Compressed container formats accepted on web ingest, by the ffmpeg demuxer that reads them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompressedFormat {
+    WebM,
+    Ogg,
+}
+
+impl CompressedFormat {
+    /// `audio/webm;codecs=opus` and `audio/ogg;codecs=opus` as sent by `MediaRecorder`.
+    pub fn from_mime(mime: &str) -> Option<CompressedFormat> {
+        match mime {
+            "audio/webm" | "video/webm" => Some(CompressedFormat::WebM),
+            "audio/ogg" | "audio/opus" => Some(CompressedFormat::Ogg),
+            _ => None,
+        }
+    }
+
+    fn demuxer(&self) -> &'static str {
+        match self {
+            CompressedFormat::WebM => "matroska",
+            CompressedFormat::Ogg => "ogg",
+        }
+    }
+}
+
+/// Long running ffmpeg process decoding one recording session to 16 kHz mono s16le.
+///
+/// MediaRecorder only sends the container header with its first chunk, so the same
+/// decoder has to be fed every chunk of a session in order.
+pub struct CompressedAudioDecoder {
+    pub format: CompressedFormat,
+    stdin: ChildStdin,
+    timestamp_millis: Arc<AtomicI64>,
+}
+
+impl CompressedAudioDecoder {
+    /// `on_pcm` is called with up to `SAMPLE_SIZE` decoded samples as raw bytes and the
+    /// recording timestamp of the latest chunk written.
+    pub fn spawn<F>(format: CompressedFormat, mut on_pcm: F) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: FnMut(&[u8], i64) + Send + 'static,
+    {
+        let mut child = Command::new("ffmpeg")
+            .args([
+                "-hide_banner",
+                "-loglevel", "error",
+                "-f", format.demuxer(),
+                "-i", "pipe:0",
+                "-f", "s16le",
+                "-acodec", "pcm_s16le",
+                "-ac", "1",
+                "-ar", &format!("{}", TARGET_SAMPLE_RATE),
+                "pipe:1",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().ok_or("failed to capture ffmpeg stdin")?;
+        let mut stdout = child.stdout.take().ok_or("failed to capture ffmpeg stdout")?;
+        let timestamp_millis = Arc::new(AtomicI64::new(0));
+        let timestamp_millis2 = timestamp_millis.clone();
+
+        tokio::spawn(async move {
+            const CHUNK_SIZE: usize = SAMPLE_SIZE * 2; // 2 bytes per sample
+            let mut buffer = Vec::with_capacity(CHUNK_SIZE * 2);
+            let mut read_buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                match stdout.read(&mut read_buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        buffer.extend_from_slice(&read_buf[..n]);
+                        while buffer.len() >= CHUNK_SIZE {
+                            let chunk = buffer.drain(..CHUNK_SIZE).collect::<Vec<u8>>();
+                            on_pcm(&chunk, timestamp_millis2.load(Ordering::Relaxed));
+                        }
+                    },
+                    Err(e) => {
+                        error!("error reading from ffmpeg decoder: {}", e);
+                        break;
+                    },
+                }
+            }
+            // whatever is left after the session ended
+            if buffer.len() >= 2 {
+                let len = buffer.len() / 2 * 2;
+                on_pcm(&buffer[..len], timestamp_millis2.load(Ordering::Relaxed));
+            }
+            match child.wait().await {
+                Ok(status) => debug!("ffmpeg decoder exited with status: {}", status),
+                Err(e) => error!("failed to wait for ffmpeg decoder: {}", e),
+            }
+        });
+
+        Ok(Self { format, stdin, timestamp_millis })
+    }
+
+    pub async fn write(&mut self, data: &[u8], timestamp_millis: i64) -> Result<(), Box<dyn std::error::Error>> {
+        self.timestamp_millis.store(timestamp_millis, Ordering::Relaxed);
+        self.stdin.write_all(data).await?;
+        self.stdin.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_frames_carry_over_to_the_next_chunk() {
+        let format = PcmFormat::new(TARGET_SAMPLE_RATE as u32, 2, SampleEncoding::S16le).unwrap();
+        let mut resampler = PcmResampler::new(format).unwrap();
+        let frame: Vec<u8> = [8192i16.to_le_bytes(), 8192i16.to_le_bytes()].concat();
+
+        assert!(resampler.process(&frame[..3]).unwrap().is_empty());
+        let samples = resampler.process(&frame[3..]).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0], 8192);
+        assert!(resampler.finish().unwrap().is_empty());
+    }
+}
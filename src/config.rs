@@ -1,5 +1,11 @@
 use serde::Deserialize;
 
+use crate::decoding::DecodingConfig;
+use crate::diarization::DiarizationConfig;
+use crate::filter::FilterConfig;
+use crate::postprocess::PostProcessConfig;
+use crate::prompt::PromptConfig;
+
 #[derive(Deserialize)]
 pub struct DatabaseConfig {
     pub database_host: String,
@@ -18,13 +24,47 @@ pub enum Source {
     Web, // get microphone input from a web page
 }
 
+// [wyoming] section, only used by the wyoming subcommand
+#[derive(Deserialize)]
+pub struct WyomingConfig {
+    // defaults to 0.0.0.0 and 10300
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub model: Option<String>,
+    // defaults to language of the config
+    pub language: Option<String>,
+    // e.g. names of rooms and devices, [wyoming.prompt]
+    pub prompt: Option<PromptConfig>,
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     pub source: Source,
     pub url: Option<String>,
     pub database_config: Option<DatabaseConfig>,
+    // a whisper language code, or "auto" to detect it for every speech clip
     pub language: String,
+    // detect among these only, also turns detection on
+    pub allowed_languages: Option<Vec<String>>,
     pub show_name: String,
+    // save the speech clips transcripts came from here, for playback from the web page
+    pub audio_archive_dir: Option<String>,
+    pub wyoming: Option<WyomingConfig>,
+    // drop or flag whisper hallucinations before saving, off without the section
+    pub filter: Option<FilterConfig>,
+    // initial prompt and key terms given to every whisper call of the show
+    pub prompt: Option<PromptConfig>,
+    // sampling strategy and thresholds of whisper, whisper.cpp defaults without the section
+    #[serde(default)]
+    pub decoding: DecodingConfig,
+    // also save an english translation of every transcript, from a second whisper pass
+    #[serde(default)]
+    pub translate: bool,
+    // text transforms of everything saved and returned, zh and yue to zh-Hant without the section
+    #[serde(default)]
+    pub postprocess: PostProcessConfig,
+    // label the speaker of every transcript with a speaker embedding model, off without the section
+    pub diarization: Option<DiarizationConfig>,
    //port: Option<u16>,
    //keys: Keys,
 }
@@ -0,0 +1,148 @@
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use serde::Serialize;
+use sqlx::{Pool, Postgres, Row};
+
+use crate::config::Config;
+use crate::db::init_db_from_config;
+use crate::runtime_utils::get_runtime;
+use crate::search::search_text;
+
+#[derive(Serialize)]
+pub struct TranscriptRevision {
+    pub id: i64,
+    pub transcript_id: i64,
+    pub editor: String,
+    pub previous_content: String,
+    pub content: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct RevisionHistory {
+    pub transcript_id: i64,
+    // machine output, never changed by corrections
+    pub original_content: String,
+    pub current_content: String,
+    // oldest first
+    pub revisions: Vec<TranscriptRevision>,
+}
+
+#[derive(Serialize)]
+pub struct CorrectedTranscript {
+    pub id: i64,
+    pub show_name: String,
+    pub timestamp: String,
+    pub original_content: String,
+    pub corrected_content: String,
+    pub audio_path: Option<String>,
+    pub audio_start_ms: Option<i64>,
+    pub audio_end_ms: Option<i64>,
+}
+
+fn revision_from_row(row: &sqlx::postgres::PgRow) -> Result<TranscriptRevision, Box<dyn std::error::Error>> {
+    let created_at: NaiveDateTime = row.try_get("created_at")?;
+    Ok(TranscriptRevision {
+        id: row.try_get("id")?,
+        transcript_id: row.try_get("transcript_id")?,
+        editor: row.try_get("editor")?,
+        previous_content: row.try_get("previous_content")?,
+        content: row.try_get("new_content")?,
+        created_at: created_at.and_utc().to_rfc3339(),
+    })
+}
+
+/// Save `content` as the corrected text of a transcript and record the change as a new revision.
+///
+/// Returns None if the transcript does not exist.
+pub async fn correct_transcript(pool: &Pool<Postgres>, transcript_id: i64, content: &str, editor: &str) -> Result<Option<TranscriptRevision>, Box<dyn std::error::Error>> {
+    let mut tx = pool.begin().await?;
+
+    // lock the row so concurrent edits are recorded one after the other
+    let row = sqlx::query(r#"SELECT coalesce(corrected_content, content) AS current_content FROM transcripts WHERE id = $1 FOR UPDATE"#)
+        .bind(transcript_id)
+        .fetch_optional(&mut *tx).await?;
+    let previous_content: String = match row {
+        Some(row) => row.try_get("current_content")?,
+        None => return Ok(None),
+    };
+
+    let row = sqlx::query(r#"INSERT INTO transcript_revisions (transcript_id, editor, previous_content, new_content)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, transcript_id, editor, previous_content, new_content, created_at"#)
+        .bind(transcript_id)
+        .bind(editor)
+        .bind(previous_content.as_str())
+        .bind(content)
+        .fetch_one(&mut *tx).await?;
+    let revision = revision_from_row(&row)?;
+
+    sqlx::query(r#"UPDATE transcripts SET corrected_content = $1, search_text = $2 WHERE id = $3"#)
+        .bind(content)
+        .bind(search_text(content))
+        .bind(transcript_id)
+        .execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(Some(revision))
+}
+
+pub async fn get_revision_history(pool: &Pool<Postgres>, transcript_id: i64) -> Result<Option<RevisionHistory>, Box<dyn std::error::Error>> {
+    let row = sqlx::query(r#"SELECT content, coalesce(corrected_content, content) AS current_content FROM transcripts WHERE id = $1"#)
+        .bind(transcript_id)
+        .fetch_optional(pool).await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let rows = sqlx::query(r#"SELECT id, transcript_id, editor, previous_content, new_content, created_at
+        FROM transcript_revisions WHERE transcript_id = $1 ORDER BY id"#)
+        .bind(transcript_id)
+        .fetch_all(pool).await?;
+    let revisions = rows.iter().map(revision_from_row).collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Some(RevisionHistory {
+        transcript_id,
+        original_content: row.try_get("content")?,
+        current_content: row.try_get("current_content")?,
+        revisions,
+    }))
+}
+
+/// Corrected transcripts with their original machine output, for evaluation.
+pub async fn export_corrections(pool: &Pool<Postgres>, show_name: Option<&str>, from: Option<DateTime<FixedOffset>>, to: Option<DateTime<FixedOffset>>) -> Result<Vec<CorrectedTranscript>, Box<dyn std::error::Error>> {
+    let rows = sqlx::query(r#"SELECT id, show_name, "timestamp", content, corrected_content, audio_path, audio_start_ms, audio_end_ms
+        FROM transcripts
+        WHERE corrected_content IS NOT NULL
+        AND ($1::varchar IS NULL OR show_name = $1)
+        AND ($2::timestamp IS NULL OR "timestamp" >= $2)
+        AND ($3::timestamp IS NULL OR "timestamp" < $3)
+        ORDER BY show_name, "timestamp", id"#)
+        .bind(show_name)
+        .bind(from.map(|ts| ts.naive_utc()))
+        .bind(to.map(|ts| ts.naive_utc()))
+        .fetch_all(pool).await?;
+
+    let mut results = Vec::with_capacity(rows.len());
+    for row in rows {
+        let timestamp: NaiveDateTime = row.try_get("timestamp")?;
+        results.push(CorrectedTranscript {
+            id: row.try_get("id")?,
+            show_name: row.try_get("show_name")?,
+            timestamp: timestamp.and_utc().to_rfc3339(),
+            original_content: row.try_get("content")?,
+            corrected_content: row.try_get("corrected_content")?,
+            audio_path: row.try_get("audio_path")?,
+            audio_start_ms: row.try_get("audio_start_ms")?,
+            audio_end_ms: row.try_get("audio_end_ms")?,
+        });
+    }
+    Ok(results)
+}
+
+pub fn export_corrections_from_config(config: &Config, show_name: Option<&str>, from: Option<DateTime<FixedOffset>>, to: Option<DateTime<FixedOffset>>) -> Result<Vec<CorrectedTranscript>, Box<dyn std::error::Error>> {
+    let rt = get_runtime();
+    let database_config = config.database_config.as_ref().ok_or("database_config is required to export corrections")?;
+    let pool = init_db_from_config(rt, database_config)?;
+    rt.block_on(export_corrections(&pool, show_name, from, to))
+}
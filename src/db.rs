@@ -0,0 +1,533 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+use log::debug;
+use serde::Serialize;
+use sqlx::postgres::{PgConnectOptions, PgExecutor, PgPoolOptions};
+use sqlx::{ConnectOptions, Pool, Postgres, QueryBuilder, Row};
+use tokio::runtime::Runtime;
+
+use crate::audio_archive::TranscriptAudio;
+use crate::vad_processor::{SegmentConfidence, WordTimestamp};
+use crate::config::DatabaseConfig;
+use crate::key_ring_utils::get_password;
+use crate::search::search_text;
+
+pub fn init_db_from_config(rt: &Runtime,database_config: &DatabaseConfig) -> Result<Pool<sqlx::Postgres>, Box<dyn std::error::Error>> {
+    init_db(rt, database_config, true)
+}
+
+/// Like `init_db_from_config` without filling in columns of old rows, for one off cli commands.
+pub fn init_db_without_backfill(rt: &Runtime,database_config: &DatabaseConfig) -> Result<Pool<sqlx::Postgres>, Box<dyn std::error::Error>> {
+    init_db(rt, database_config, false)
+}
+
+fn init_db(rt: &Runtime,database_config: &DatabaseConfig, backfill: bool) -> Result<Pool<sqlx::Postgres>, Box<dyn std::error::Error>> {
+    let database_password = get_password(&database_config.database_password_key)?;
+    //println!("My password is '{}'", database_password);
+
+    rt.block_on(async {
+        let ssl_mode = match database_config.require_ssl {
+            true => sqlx::postgres::PgSslMode::Require,
+            _ => sqlx::postgres::PgSslMode::Prefer
+        };
+
+        let options = PgConnectOptions::new()
+            .ssl_mode(ssl_mode)
+            .host(&database_config.database_host)
+            .port(database_config.database_port.unwrap_or(5432))
+            .database(database_config.database_name.as_str())
+            .username(database_config.database_user.as_str())
+            .password(database_password.as_str())
+            .log_statements(log::LevelFilter::Trace);
+
+        let pool2 = PgPoolOptions::new().connect_with(options).await?;
+        sqlx::query(r#"CREATE TABLE IF NOT EXISTS transcripts (
+            id bigserial PRIMARY KEY,
+            show_name varchar(255) NOT NULL,
+            "timestamp" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+            content TEXT NOT NULL
+            );"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"create index if not exists transcript_show_name_idx ON transcripts (show_name);"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"create index if not exists transcript_show_name_timestamp_idx ON transcripts (show_name, "timestamp");"#
+            ).execute(&pool2).await?;
+
+        // full text search, search_text holds the tokens produced by search::search_text
+        // so that cjk text can be matched with the 'simple' configuration
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS search_text TEXT;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS search_vector tsvector
+            GENERATED ALWAYS AS (to_tsvector('simple', coalesce(search_text, ''))) STORED;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"create index if not exists transcript_search_vector_idx ON transcripts USING GIN (search_vector);"#
+            ).execute(&pool2).await?;
+
+        // only rows from before full text search, so checking for them is a lookup in an empty index
+        sqlx::query(r#"create index if not exists transcript_search_text_missing_idx ON transcripts (id) WHERE search_text IS NULL;"#
+            ).execute(&pool2).await?;
+        if backfill {
+            backfill_search_text(&pool2).await?;
+        }
+
+        // archived speech clip the transcript came from, see audio_archive.rs
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS audio_path TEXT;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS audio_start_ms BIGINT;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS audio_end_ms BIGINT;"#
+            ).execute(&pool2).await?;
+
+        // per show statistics kept up to date by insert_transcript, see shows.rs
+        sqlx::query(r#"CREATE TABLE IF NOT EXISTS shows (
+            show_name varchar(255) PRIMARY KEY,
+            row_count BIGINT NOT NULL DEFAULT 0,
+            first_timestamp TIMESTAMP WITHOUT TIME ZONE,
+            last_timestamp TIMESTAMP WITHOUT TIME ZONE,
+            language varchar(32),
+            model varchar(255),
+            speech_seconds DOUBLE PRECISION NOT NULL DEFAULT 0,
+            last_heartbeat_at TIMESTAMP WITHOUT TIME ZONE
+            );"#
+            ).execute(&pool2).await?;
+        if backfill {
+            backfill_shows(&pool2).await?;
+        }
+
+        // corrections by editors, content always keeps the machine output, see corrections.rs
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS corrected_content TEXT;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"CREATE TABLE IF NOT EXISTS transcript_revisions (
+            id bigserial PRIMARY KEY,
+            transcript_id BIGINT NOT NULL REFERENCES transcripts (id) ON DELETE CASCADE,
+            editor varchar(255) NOT NULL,
+            previous_content TEXT NOT NULL,
+            new_content TEXT NOT NULL,
+            created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT (now() at time zone 'utc')
+            );"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"create index if not exists transcript_revisions_transcript_id_idx ON transcript_revisions (transcript_id);"#
+            ).execute(&pool2).await?;
+
+        // [{"word", "start_ms", "end_ms", "probability"}] with times relative to "timestamp"
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS words JSONB;"#
+            ).execute(&pool2).await?;
+
+        // see SegmentConfidence, null for transcripts saved before they were recorded
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS avg_logprob REAL;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS min_token_prob REAL;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS no_speech_prob REAL;"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS vad_probability REAL;"#
+            ).execute(&pool2).await?;
+
+        // why the hallucination filter flagged a transcript, see filter.rs
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS filter_reason varchar(32);"#
+            ).execute(&pool2).await?;
+
+        // english translation from the translate task of whisper
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS translation TEXT;"#
+            ).execute(&pool2).await?;
+
+        // whisper's text before post processing, content is what was made of it
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS raw_content TEXT;"#
+            ).execute(&pool2).await?;
+
+        // language of the row, detected per speech clip with language = "auto"
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS language varchar(16);"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS language_probability REAL;"#
+            ).execute(&pool2).await?;
+
+        // speaker_1, speaker_2 and so on from diarization, only unique within one show run or file
+        sqlx::query(r#"ALTER TABLE transcripts ADD COLUMN IF NOT EXISTS speaker varchar(32);"#
+            ).execute(&pool2).await?;
+
+        // durable queue for batch transcription, see job_queue.rs
+        sqlx::query(r#"CREATE TABLE IF NOT EXISTS transcription_jobs (
+            id bigserial PRIMARY KEY,
+            source TEXT NOT NULL,
+            show_name varchar(255) NOT NULL,
+            model varchar(255) NOT NULL,
+            language varchar(32) NOT NULL,
+            priority INTEGER NOT NULL DEFAULT 0,
+            status varchar(32) NOT NULL DEFAULT 'queued',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            max_attempts INTEGER NOT NULL DEFAULT 3,
+            lease_owner varchar(255),
+            lease_expires_at TIMESTAMP WITHOUT TIME ZONE,
+            last_error TEXT,
+            recorded_at TIMESTAMP WITHOUT TIME ZONE,
+            created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT (now() at time zone 'utc'),
+            started_at TIMESTAMP WITHOUT TIME ZONE,
+            finished_at TIMESTAMP WITHOUT TIME ZONE
+            );"#
+            ).execute(&pool2).await?;
+        sqlx::query(r#"create index if not exists transcription_jobs_claim_idx ON transcription_jobs (status, priority DESC, id);"#
+            ).execute(&pool2).await?;
+
+        Ok::<Pool<Postgres>, Box<dyn std::error::Error>>(pool2)
+    })
+}
+
+// fill in search_text for rows saved before full text search was added
+async fn backfill_search_text(pool: &Pool<Postgres>) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let rows = sqlx::query(r#"SELECT id, content FROM transcripts WHERE search_text IS NULL ORDER BY id LIMIT 1000"#)
+            .fetch_all(pool).await?;
+        if rows.is_empty() {
+            break;
+        }
+        debug!("backfilling search_text for {} rows", rows.len());
+        let mut updates = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id: i64 = row.try_get("id")?;
+            let content: String = row.try_get("content")?;
+            updates.push((id, search_text(&content)));
+        }
+        let mut query = QueryBuilder::<Postgres>::new("UPDATE transcripts SET search_text = v.search_text FROM (");
+        query.push_values(updates, |mut row, (id, search_text)| {
+            row.push_bind(id).push_bind(search_text);
+        });
+        query.push(") AS v (id, search_text) WHERE transcripts.id = v.id");
+        query.build().execute(pool).await?;
+    }
+    Ok(())
+}
+
+// count the shows that only have rows saved before the shows table was added, including
+// ones a live ingest has already added with its heartbeat
+async fn backfill_shows(pool: &Pool<Postgres>) -> Result<(), Box<dyn std::error::Error>> {
+    // one lookup in transcript_show_name_idx per show instead of a scan of every row
+    let rows = sqlx::query(r#"WITH RECURSIVE names AS (
+            (SELECT show_name FROM transcripts ORDER BY show_name LIMIT 1)
+            UNION ALL
+            SELECT (SELECT show_name FROM transcripts WHERE show_name > names.show_name ORDER BY show_name LIMIT 1)
+            FROM names WHERE names.show_name IS NOT NULL
+        )
+        SELECT show_name FROM names
+        WHERE show_name IS NOT NULL
+        AND NOT EXISTS (SELECT 1 FROM shows WHERE shows.show_name = names.show_name AND shows.row_count > 0)"#)
+        .fetch_all(pool).await?;
+    for row in rows {
+        let show_name: String = row.try_get("show_name")?;
+        debug!("backfilling show statistics for {}", show_name);
+        // the archived clip span is the only duration old rows have, rows without audio count as none
+        sqlx::query(r#"INSERT INTO shows (show_name, row_count, first_timestamp, last_timestamp, speech_seconds)
+            SELECT show_name, count(*), min("timestamp"), max("timestamp"),
+                coalesce(sum(greatest(audio_end_ms - audio_start_ms, 0)), 0) / 1000.0
+            FROM transcripts
+            WHERE show_name = $1
+            GROUP BY show_name
+            ON CONFLICT (show_name) DO UPDATE SET
+                row_count = excluded.row_count,
+                first_timestamp = excluded.first_timestamp,
+                last_timestamp = excluded.last_timestamp,
+                speech_seconds = excluded.speech_seconds
+            WHERE shows.row_count = 0"#)
+            .bind(&show_name)
+            .execute(pool).await?;
+    }
+    Ok(())
+}
+
+pub struct NewTranscript<'a> {
+    pub show_name: &'a str,
+    pub timestamp: DateTime<Utc>,
+    pub content: &'a str,
+    // before post processing
+    pub raw_content: &'a str,
+    // length of the speech the transcript covers, for the show statistics
+    pub duration_ms: i64,
+    pub audio: Option<&'a TranscriptAudio>,
+    // relative to timestamp
+    pub words: &'a [WordTimestamp],
+    pub confidence: &'a SegmentConfidence,
+    // set when the filter flagged it instead of dropping it
+    pub filter_reason: Option<&'a str>,
+    pub translation: Option<&'a str>,
+    pub language: &'a str,
+    // only for detected languages
+    pub language_probability: Option<f32>,
+    pub speaker: Option<&'a str>,
+}
+
+pub async fn insert_transcript<'c, E>(executor: E, transcript: &NewTranscript<'_>) -> Result<(), Box<dyn std::error::Error>>
+where
+    E: PgExecutor<'c>,
+{
+    // one statement so it also works with a transaction as the executor
+    let sql = r#"WITH inserted AS (
+            INSERT INTO transcripts (show_name,"timestamp", content, search_text, audio_path, audio_start_ms, audio_end_ms, words,
+                avg_logprob, min_token_prob, no_speech_prob, vad_probability, filter_reason, translation,
+                language, language_probability, raw_content, speaker)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $9::jsonb, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)
+            RETURNING show_name, "timestamp"
+        )
+        INSERT INTO shows (show_name, row_count, first_timestamp, last_timestamp, speech_seconds)
+        SELECT show_name, 1, "timestamp", "timestamp", $8 FROM inserted
+        ON CONFLICT (show_name) DO UPDATE SET
+            row_count = shows.row_count + 1,
+            first_timestamp = least(shows.first_timestamp, excluded.first_timestamp),
+            last_timestamp = greatest(shows.last_timestamp, excluded.last_timestamp),
+            speech_seconds = shows.speech_seconds + excluded.speech_seconds"#;
+    sqlx::query(
+        sql,
+    )
+    .bind(transcript.show_name)
+    .bind(transcript.timestamp)
+    .bind(transcript.content)
+    .bind(search_text(transcript.content))
+    .bind(transcript.audio.map(|audio| audio.path.as_str()))
+    .bind(transcript.audio.map(|audio| audio.start_ms))
+    .bind(transcript.audio.map(|audio| audio.end_ms))
+    .bind(transcript.duration_ms.max(0) as f64 / 1000.0)
+    .bind(serde_json::to_string(transcript.words)?)
+    .bind(transcript.confidence.avg_logprob)
+    .bind(transcript.confidence.min_token_prob)
+    .bind(transcript.confidence.no_speech_prob)
+    .bind(transcript.confidence.vad_probability)
+    .bind(transcript.filter_reason)
+    .bind(transcript.translation)
+    .bind(transcript.language)
+    .bind(transcript.language_probability)
+    .bind(transcript.raw_content)
+    .bind(transcript.speaker)
+    .execute(executor).await?;
+    Ok(())
+}
+
+pub async fn get_transcript_audio(pool: &Pool<Postgres>, id: i64) -> Result<Option<TranscriptAudio>, Box<dyn std::error::Error>> {
+    let row = sqlx::query(r#"SELECT audio_path, audio_start_ms, audio_end_ms FROM transcripts WHERE id = $1 AND audio_path IS NOT NULL"#)
+        .bind(id)
+        .fetch_optional(pool).await?;
+    match row {
+        Some(row) => Ok(Some(TranscriptAudio {
+            path: row.try_get("audio_path")?,
+            start_ms: row.try_get("audio_start_ms")?,
+            end_ms: row.try_get("audio_end_ms")?,
+        })),
+        None => Ok(None),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+pub struct TimeRangeQuery {
+    pub show_name: String,
+    // utc, inclusive
+    pub start: Option<NaiveDateTime>,
+    // utc, exclusive
+    pub end: Option<NaiveDateTime>,
+    pub cursor: Option<(NaiveDateTime, i64)>,
+    pub limit: i64,
+    pub order: SortOrder,
+    pub include_words: bool,
+    pub hide_low_confidence: bool,
+}
+
+// same as whisper's logprob_thold and no_speech_thold
+pub const LOW_CONFIDENCE_AVG_LOGPROB: f32 = -1.0;
+pub const LOW_CONFIDENCE_NO_SPEECH_PROB: f32 = 0.6;
+
+#[derive(Serialize)]
+pub struct TranscriptRow {
+    pub id: i64,
+    pub timestamp: String,
+    pub content: String,
+    // whisper's text before post processing, none for rows saved before it was kept
+    pub raw_content: Option<String>,
+    // only when asked for, timings of the original machine output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub words: Option<Vec<WordTimestamp>>,
+    pub avg_logprob: Option<f32>,
+    pub min_token_prob: Option<f32>,
+    pub no_speech_prob: Option<f32>,
+    pub vad_probability: Option<f32>,
+    pub filter_reason: Option<String>,
+    pub translation: Option<String>,
+    // rows saved before languages were stored have none
+    pub language: Option<String>,
+    pub language_probability: Option<f32>,
+    // with diarization
+    pub speaker: Option<String>,
+    // also set for rows flagged by the filter
+    pub low_confidence: bool,
+}
+
+#[derive(Serialize)]
+pub struct TranscriptPage {
+    pub transcripts: Vec<TranscriptRow>,
+    pub next_cursor: Option<String>,
+}
+
+/// Put back the `+` of a utc offset at the end of a query parameter, which an unescaped `+`
+/// in a query string decodes to a space, e.g. `" 08:00"` or `"2024-10-01T08:00:00 08:00"`.
+pub fn restore_offset_plus(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let n = bytes.len();
+    let is_offset = n >= 6 && bytes[n - 6] == b' '
+        && bytes[n - 5].is_ascii_digit() && bytes[n - 4].is_ascii_digit() && bytes[n - 3] == b':'
+        && bytes[n - 2].is_ascii_digit() && bytes[n - 1].is_ascii_digit();
+    if is_offset {
+        format!("{}+{}", &value[..n - 6], &value[n - 5..])
+    } else {
+        value.to_string()
+    }
+}
+
+/// Parse a time parameter, either rfc3339 with its own offset or a naive
+/// `YYYY-MM-DDTHH:MM:SS` / `YYYY-MM-DD` interpreted in `tz`. Returns utc.
+pub fn parse_time_param(value: &str, tz: &FixedOffset) -> Result<NaiveDateTime, Box<dyn std::error::Error>> {
+    let value = restore_offset_plus(value);
+    let value = value.as_str();
+    if let Ok(ts) = DateTime::parse_from_rfc3339(value) {
+        return Ok(ts.naive_utc());
+    }
+    let naive = match NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        Ok(naive) => naive,
+        Err(_) => match NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
+            Ok(naive) => naive,
+            Err(_) => NaiveDate::parse_from_str(value, "%Y-%m-%d")?.and_hms_opt(0, 0, 0).unwrap(),
+        },
+    };
+    let local = naive.and_local_timezone(*tz).single().ok_or("ambiguous local time")?;
+    Ok(local.naive_utc())
+}
+
+// cursor is "<timestamp micros>_<id>" of the last row of the previous page
+fn format_cursor(timestamp: &NaiveDateTime, id: i64) -> String {
+    format!("{}_{}", timestamp.and_utc().timestamp_micros(), id)
+}
+
+pub fn parse_cursor(cursor: &str) -> Result<(NaiveDateTime, i64), Box<dyn std::error::Error>> {
+    let (micros, id) = cursor.split_once('_').ok_or("invalid cursor")?;
+    let timestamp = DateTime::from_timestamp_micros(micros.parse::<i64>()?).ok_or("invalid cursor timestamp")?;
+    Ok((timestamp.naive_utc(), id.parse::<i64>()?))
+}
+
+pub async fn query_transcripts(pool: &Pool<Postgres>, q: &TimeRangeQuery, tz: &FixedOffset) -> Result<TranscriptPage, Box<dyn std::error::Error>> {
+    let sql = match q.order {
+        SortOrder::Asc => r#"SELECT id,"timestamp",coalesce(corrected_content, content) AS content, raw_content, words::text AS words,
+                avg_logprob, min_token_prob, no_speech_prob, vad_probability, filter_reason, translation, language, language_probability, speaker,
+                (coalesce(avg_logprob < $7, false) OR coalesce(no_speech_prob > $8, false) OR filter_reason IS NOT NULL) AS low_confidence
+            FROM transcripts
+            WHERE show_name = $1
+            AND ($2::timestamp IS NULL OR "timestamp" >= $2)
+            AND ($3::timestamp IS NULL OR "timestamp" < $3)
+            AND ($4::timestamp IS NULL OR ("timestamp", id) > ($4, $5))
+            AND NOT ($9 AND (coalesce(avg_logprob < $7, false) OR coalesce(no_speech_prob > $8, false) OR filter_reason IS NOT NULL))
+            ORDER BY "timestamp", id LIMIT $6"#,
+        SortOrder::Desc => r#"SELECT id,"timestamp",coalesce(corrected_content, content) AS content, raw_content, words::text AS words,
+                avg_logprob, min_token_prob, no_speech_prob, vad_probability, filter_reason, translation, language, language_probability, speaker,
+                (coalesce(avg_logprob < $7, false) OR coalesce(no_speech_prob > $8, false) OR filter_reason IS NOT NULL) AS low_confidence
+            FROM transcripts
+            WHERE show_name = $1
+            AND ($2::timestamp IS NULL OR "timestamp" >= $2)
+            AND ($3::timestamp IS NULL OR "timestamp" < $3)
+            AND ($4::timestamp IS NULL OR ("timestamp", id) < ($4, $5))
+            AND NOT ($9 AND (coalesce(avg_logprob < $7, false) OR coalesce(no_speech_prob > $8, false) OR filter_reason IS NOT NULL))
+            ORDER BY "timestamp" DESC, id DESC LIMIT $6"#,
+    };
+
+    // fetch one extra row to know whether there is a next page
+    let rows = sqlx::query(sql)
+        .bind(q.show_name.as_str())
+        .bind(q.start)
+        .bind(q.end)
+        .bind(q.cursor.map(|(ts, _)| ts))
+        .bind(q.cursor.map(|(_, id)| id).unwrap_or(0))
+        .bind(q.limit + 1)
+        .bind(LOW_CONFIDENCE_AVG_LOGPROB)
+        .bind(LOW_CONFIDENCE_NO_SPEECH_PROB)
+        .bind(q.hide_low_confidence)
+        .fetch_all(pool).await?;
+
+    let has_more = rows.len() as i64 > q.limit;
+    let mut transcripts = Vec::with_capacity(rows.len());
+    let mut last: Option<(NaiveDateTime, i64)> = None;
+    for row in rows.into_iter().take(q.limit as usize) {
+        let id: i64 = row.try_get("id")?;
+        let timestamp: NaiveDateTime = row.try_get("timestamp")?;
+        let words = if q.include_words {
+            // transcripts saved before word timestamps have none
+            let words: Option<String> = row.try_get("words")?;
+            Some(match words {
+                Some(words) => serde_json::from_str(&words)?,
+                None => Vec::new(),
+            })
+        } else {
+            None
+        };
+        transcripts.push(TranscriptRow {
+            id,
+            timestamp: timestamp.and_utc().with_timezone(tz).to_rfc3339(),
+            content: row.try_get("content")?,
+            raw_content: row.try_get("raw_content")?,
+            words,
+            avg_logprob: row.try_get("avg_logprob")?,
+            min_token_prob: row.try_get("min_token_prob")?,
+            no_speech_prob: row.try_get("no_speech_prob")?,
+            vad_probability: row.try_get("vad_probability")?,
+            filter_reason: row.try_get("filter_reason")?,
+            translation: row.try_get("translation")?,
+            language: row.try_get("language")?,
+            language_probability: row.try_get("language_probability")?,
+            speaker: row.try_get("speaker")?,
+            low_confidence: row.try_get("low_confidence")?,
+        });
+        last = Some((timestamp, id));
+    }
+
+    let next_cursor = match (has_more, last) {
+        (true, Some((timestamp, id))) => Some(format_cursor(&timestamp, id)),
+        _ => None,
+    };
+
+    Ok(TranscriptPage { transcripts, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trip() {
+        let timestamp = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap().and_hms_micro_opt(12, 30, 15, 123456).unwrap();
+        let cursor = format_cursor(&timestamp, 42);
+        assert_eq!(cursor, "1709296215123456_42");
+        assert_eq!(parse_cursor(&cursor).unwrap(), (timestamp, 42));
+    }
+
+    #[test]
+    fn malformed_cursor() {
+        for cursor in ["", "_", "1709296215123456", "1709296215123456_", "_42", "abc_42", "1709296215123456_x", "1_2_3", &format!("{}_1", i64::MAX)] {
+            assert!(parse_cursor(cursor).is_err(), "{}", cursor);
+        }
+    }
+
+    #[test]
+    fn offset_plus_decoded_to_a_space() {
+        assert_eq!(restore_offset_plus("2024-01-01T08:00:00 08:00"), "2024-01-01T08:00:00+08:00");
+        assert_eq!(restore_offset_plus(" 08:00"), "+08:00");
+        assert_eq!(restore_offset_plus("2024-01-01T08:00:00-08:00"), "2024-01-01T08:00:00-08:00");
+        assert_eq!(restore_offset_plus("2024-01-01 08:00:00"), "2024-01-01 08:00:00");
+        assert_eq!(restore_offset_plus(""), "");
+    }
+
+    #[test]
+    fn time_param_in_utc() {
+        let tz = FixedOffset::east_opt(8 * 3600).unwrap();
+        let utc = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        assert_eq!(parse_time_param("2024-01-01T08:00:00+08:00", &tz).unwrap(), utc("2024-01-01 00:00:00"));
+        assert_eq!(parse_time_param("2024-01-01T08:00:00 08:00", &tz).unwrap(), utc("2024-01-01 00:00:00"));
+        assert_eq!(parse_time_param("2024-01-01T08:00:00Z", &tz).unwrap(), utc("2024-01-01 08:00:00"));
+        assert_eq!(parse_time_param("2024-01-01", &tz).unwrap(), utc("2023-12-31 16:00:00"));
+        assert!(parse_time_param("yesterday", &tz).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use serde::Deserialize;
+use whisper_rs::{FullParams, SamplingStrategy};
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecodingStrategy {
+    Greedy,
+    BeamSearch,
+}
+
+/// `[decoding]` section of the config, how whisper decodes every speech clip and file.
+///
+/// Thresholds left out keep the whisper.cpp defaults.
+#[derive(Clone, Deserialize)]
+pub struct DecodingConfig {
+    #[serde(default = "default_strategy")]
+    pub strategy: DecodingStrategy,
+    // candidates sampled per fallback temperature, greedy only
+    #[serde(default = "default_best_of")]
+    pub best_of: i32,
+    #[serde(default = "default_beam_size")]
+    pub beam_size: i32,
+    // beam search only, whisper.cpp doesn't use it yet
+    pub patience: Option<f32>,
+    // 0.0 picks the most likely token
+    pub temperature: Option<f32>,
+    // added to the temperature on every retry of a failed decode, 0.0 turns the fallback off
+    pub temperature_inc: Option<f32>,
+    // retry when the entropy of the tokens is above this, i.e. a loop, whisper.cpp default 2.4
+    pub entropy_thold: Option<f32>,
+    // retry when the mean token log probability is below this, whisper.cpp default -1.0
+    pub logprob_thold: Option<f32>,
+    // treat as silence when the no speech probability is above this, whisper.cpp default 0.6
+    pub no_speech_thold: Option<f32>,
+    pub suppress_blank: Option<bool>,
+    pub suppress_non_speech_tokens: Option<bool>,
+    // characters per segment, split on words, 0 for no limit
+    #[serde(default)]
+    pub max_segment_chars: i32,
+}
+
+fn default_strategy() -> DecodingStrategy {
+    DecodingStrategy::Greedy
+}
+
+fn default_best_of() -> i32 {
+    5
+}
+
+fn default_beam_size() -> i32 {
+    5
+}
+
+impl Default for DecodingConfig {
+    fn default() -> Self {
+        Self {
+            strategy: default_strategy(),
+            best_of: default_best_of(),
+            beam_size: default_beam_size(),
+            patience: None,
+            temperature: None,
+            temperature_inc: None,
+            entropy_thold: None,
+            logprob_thold: None,
+            no_speech_thold: None,
+            suppress_blank: None,
+            suppress_non_speech_tokens: None,
+            max_segment_chars: 0,
+        }
+    }
+}
+
+impl DecodingConfig {
+    /// Checked when the config is loaded, so a typo fails at startup instead of on the first clip.
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !(1..=8).contains(&self.best_of) {
+            return Err(format!("decoding.best_of must be between 1 and 8, got {}", self.best_of).into());
+        }
+        if !(1..=8).contains(&self.beam_size) {
+            return Err(format!("decoding.beam_size must be between 1 and 8, got {}", self.beam_size).into());
+        }
+        if let Some(patience) = self.patience {
+            if patience <= 0.0 {
+                return Err(format!("decoding.patience must be above 0, got {}", patience).into());
+            }
+        }
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=1.0).contains(&temperature) {
+                return Err(format!("decoding.temperature must be between 0 and 1, got {}", temperature).into());
+            }
+        }
+        if let Some(temperature_inc) = self.temperature_inc {
+            if !(0.0..=1.0).contains(&temperature_inc) {
+                return Err(format!("decoding.temperature_inc must be between 0 and 1, got {}", temperature_inc).into());
+            }
+        }
+        if let Some(entropy_thold) = self.entropy_thold {
+            if entropy_thold <= 0.0 {
+                return Err(format!("decoding.entropy_thold must be above 0, got {}", entropy_thold).into());
+            }
+        }
+        if let Some(logprob_thold) = self.logprob_thold {
+            if logprob_thold > 0.0 {
+                return Err(format!("decoding.logprob_thold is a log probability, it can't be above 0, got {}", logprob_thold).into());
+            }
+        }
+        if let Some(no_speech_thold) = self.no_speech_thold {
+            if !(0.0..=1.0).contains(&no_speech_thold) {
+                return Err(format!("decoding.no_speech_thold must be between 0 and 1, got {}", no_speech_thold).into());
+            }
+        }
+        if self.max_segment_chars < 0 {
+            return Err(format!("decoding.max_segment_chars can't be negative, got {}", self.max_segment_chars).into());
+        }
+        Ok(())
+    }
+
+    pub fn sampling_strategy(&self) -> SamplingStrategy {
+        match self.strategy {
+            DecodingStrategy::Greedy => SamplingStrategy::Greedy { best_of: self.best_of },
+            DecodingStrategy::BeamSearch => SamplingStrategy::BeamSearch {
+                beam_size: self.beam_size,
+                // whisper.cpp default
+                patience: self.patience.unwrap_or(-1.0),
+            },
+        }
+    }
+
+    /// Set everything but the sampling strategy, which `FullParams` is created with.
+    pub fn apply(&self, params: &mut FullParams) {
+        if let Some(temperature) = self.temperature {
+            params.set_temperature(temperature);
+        }
+        if let Some(temperature_inc) = self.temperature_inc {
+            params.set_temperature_inc(temperature_inc);
+        }
+        if let Some(entropy_thold) = self.entropy_thold {
+            params.set_entropy_thold(entropy_thold);
+        }
+        if let Some(logprob_thold) = self.logprob_thold {
+            params.set_logprob_thold(logprob_thold);
+        }
+        if let Some(no_speech_thold) = self.no_speech_thold {
+            params.set_no_speech_thold(no_speech_thold);
+        }
+        if let Some(suppress_blank) = self.suppress_blank {
+            params.set_suppress_blank(suppress_blank);
+        }
+        if let Some(suppress_non_speech_tokens) = self.suppress_non_speech_tokens {
+            params.set_suppress_non_speech_tokens(suppress_non_speech_tokens);
+        }
+        if self.max_segment_chars > 0 {
+            params.set_max_len(self.max_segment_chars);
+            params.set_split_on_word(true);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use ort::{GraphOptimizationLevel, Session};
+use serde::Deserialize;
+
+use crate::download_utils::get_whisper_model;
+use crate::postprocess::is_cjk;
+use crate::vad_processor::{TranscriptSegment, WordTimestamp, TARGET_SAMPLE_RATE};
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmbeddingInput {
+    // 80 kaldi log mel filterbanks of 16 bit sample values, [1, frames, 80], e.g. wespeaker and 3d-speaker
+    Fbank,
+    // raw samples between -1 and 1, [1, 1, samples], e.g. pyannote/embedding
+    Waveform,
+}
+
+/// `[diarization]` section of the config, labels who is talking, off without it.
+#[derive(Clone, Deserialize)]
+pub struct DiarizationConfig {
+    // local path or url of a speaker embedding onnx model, urls are downloaded once
+    pub model: String,
+    #[serde(default = "default_input")]
+    pub input: EmbeddingInput,
+    // cosine similarity to the closest speaker to count as that speaker
+    #[serde(default = "default_threshold")]
+    pub threshold: f32,
+    // after this many the closest one is used
+    pub max_speakers: Option<usize>,
+    // shorter speech is labelled with the embedding of its whole speech clip
+    #[serde(default = "default_min_speech_ms")]
+    pub min_speech_ms: i64,
+    // split segments where the speaker changes, in windows of words of about window_ms
+    #[serde(default)]
+    pub split_on_speaker_change: bool,
+    #[serde(default = "default_window_ms")]
+    pub window_ms: i64,
+}
+
+fn default_input() -> EmbeddingInput {
+    EmbeddingInput::Fbank
+}
+
+fn default_threshold() -> f32 {
+    0.5
+}
+
+fn default_min_speech_ms() -> i64 {
+    1000
+}
+
+fn default_window_ms() -> i64 {
+    1500
+}
+
+const FRAME_LENGTH: usize = 400;
+const FRAME_SHIFT: usize = 160;
+const FFT_SIZE: usize = 512;
+const NUM_MEL_BINS: usize = 80;
+
+// in place radix 2 fft, re and im are FFT_SIZE long
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_re, w_im) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+fn mel(freq: f32) -> f32 {
+    1127.0 * (1.0 + freq / 700.0).ln()
+}
+
+// triangular filters over the fft bins, 20 hz to nyquist like kaldi
+fn mel_filters() -> Vec<Vec<f32>> {
+    let (low, high) = (mel(20.0), mel(TARGET_SAMPLE_RATE as f32 / 2.0));
+    let delta = (high - low) / (NUM_MEL_BINS + 1) as f32;
+    (0..NUM_MEL_BINS).map(|bin| {
+        let (left, center, right) = (low + bin as f32 * delta, low + (bin + 1) as f32 * delta, low + (bin + 2) as f32 * delta);
+        (0..FFT_SIZE / 2).map(|i| {
+            let m = mel(i as f32 * TARGET_SAMPLE_RATE as f32 / FFT_SIZE as f32);
+            if m > left && m < center {
+                (m - left) / (center - left)
+            } else if m >= center && m < right {
+                (right - m) / (right - center)
+            } else {
+                0.0
+            }
+        }).collect()
+    }).collect()
+}
+
+// kaldi fbank with wespeaker's settings: no dither, povey window, mean normalized
+fn fbank(samples: &[i16], filters: &[Vec<f32>]) -> ndarray::Array2<f32> {
+    let num_frames = if samples.len() < FRAME_LENGTH { 0 } else { 1 + (samples.len() - FRAME_LENGTH) / FRAME_SHIFT };
+    let window: Vec<f32> = (0..FRAME_LENGTH)
+        .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f32 / (FRAME_LENGTH - 1) as f32).cos()).powf(0.85))
+        .collect();
+    let mut feats = ndarray::Array2::<f32>::zeros((num_frames, NUM_MEL_BINS));
+    let mut re = vec![0.0f32; FFT_SIZE];
+    let mut im = vec![0.0f32; FFT_SIZE];
+    for frame in 0..num_frames {
+        let samples = &samples[frame * FRAME_SHIFT..frame * FRAME_SHIFT + FRAME_LENGTH];
+        let mean = samples.iter().map(|s| *s as f32).sum::<f32>() / FRAME_LENGTH as f32;
+        re.fill(0.0);
+        im.fill(0.0);
+        for i in 0..FRAME_LENGTH {
+            let sample = samples[i] as f32 - mean;
+            let previous = if i == 0 { sample } else { samples[i - 1] as f32 - mean };
+            re[i] = (sample - 0.97 * previous) * window[i];
+        }
+        fft(&mut re, &mut im);
+        for (bin, filter) in filters.iter().enumerate() {
+            let energy: f32 = filter.iter().enumerate()
+                .map(|(i, weight)| weight * (re[i] * re[i] + im[i] * im[i]))
+                .sum();
+            feats[[frame, bin]] = energy.max(f32::EPSILON).ln();
+        }
+    }
+    if num_frames > 0 {
+        let mean = feats.mean_axis(ndarray::Axis(0)).unwrap();
+        feats -= &mean;
+    }
+    feats
+}
+
+fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 { dot / norm } else { 0.0 }
+}
+
+/// A speaker embedding onnx model, shared by every diarized show or file.
+pub struct SpeakerEmbedder {
+    session: Session,
+    config: DiarizationConfig,
+    input_name: String,
+    output_name: String,
+    filters: Vec<Vec<f32>>,
+}
+
+impl SpeakerEmbedder {
+    pub fn new(config: &DiarizationConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        if !(-1.0..=1.0).contains(&config.threshold) {
+            return Err(format!("diarization.threshold is a cosine similarity between -1 and 1, got {}", config.threshold).into());
+        }
+        if config.window_ms <= 0 {
+            return Err(format!("diarization.window_ms must be above 0, got {}", config.window_ms).into());
+        }
+        let model_path = if config.model.starts_with("http://") || config.model.starts_with("https://") {
+            get_whisper_model(&config.model)?
+        } else {
+            config.model.clone().into()
+        };
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(1)?
+            .commit_from_file(&model_path)?;
+        let input_name = session.inputs.first().ok_or("speaker embedding model without inputs")?.name.clone();
+        let output_name = session.outputs.first().ok_or("speaker embedding model without outputs")?.name.clone();
+        info!("loaded speaker embedding model {}", model_path.display());
+        Ok(Self {
+            session,
+            config: config.clone(),
+            input_name,
+            output_name,
+            filters: mel_filters(),
+        })
+    }
+
+    /// Unit length embedding of 16 khz speech.
+    pub fn embed(&self, samples: &[i16]) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let input = match self.config.input {
+            EmbeddingInput::Fbank => fbank(samples, &self.filters).insert_axis(ndarray::Axis(0)).into_dyn(),
+            EmbeddingInput::Waveform => ndarray::Array1::from_iter(samples.iter().map(|s| *s as f32 / 32768.0))
+                .into_shape_with_order((1, 1, samples.len()))?
+                .into_dyn(),
+        };
+        let outputs = self.session.run(ort::inputs![self.input_name.as_str() => input.view()]?)?;
+        let mut embedding: Vec<f32> = outputs[self.output_name.as_str()].try_extract_tensor::<f32>()?.iter().copied().collect();
+        normalize(&mut embedding);
+        Ok(embedding)
+    }
+}
+
+/// Labels the speakers of one show run or one file, speakers are only told apart within it.
+pub struct Diarizer {
+    embedder: Arc<SpeakerEmbedder>,
+    // sum of the unit embeddings of each speaker, speaker_1 first
+    speakers: Vec<Vec<f32>>,
+}
+
+fn join_words(words: &[WordTimestamp]) -> String {
+    let mut text = String::new();
+    for word in words {
+        let joins = match (text.chars().last(), word.word.chars().next()) {
+            (Some(last), Some(first)) => is_cjk(last) || is_cjk(first),
+            _ => true,
+        };
+        if !joins {
+            text.push(' ');
+        }
+        text.push_str(&word.word);
+    }
+    text
+}
+
+impl Diarizer {
+    pub fn new(embedder: Arc<SpeakerEmbedder>) -> Self {
+        Self {
+            embedder,
+            speakers: Vec::new(),
+        }
+    }
+
+    // closest speaker, or a new one when none is close enough
+    fn assign(&mut self, embedding: Vec<f32>) -> String {
+        let closest = self.speakers.iter().enumerate()
+            .map(|(i, speaker)| (i, cosine(speaker, &embedding)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let full = self.embedder.config.max_speakers.map(|max| self.speakers.len() >= max).unwrap_or(false);
+        let index = match closest {
+            Some((i, similarity)) if similarity >= self.embedder.config.threshold || full => {
+                self.speakers[i].iter_mut().zip(&embedding).for_each(|(sum, x)| *sum += x);
+                i
+            },
+            _ => {
+                self.speakers.push(embedding);
+                debug!("new speaker {}", self.speakers.len());
+                self.speakers.len() - 1
+            },
+        };
+        format!("speaker_{}", index + 1)
+    }
+
+    fn slice(samples: &[i16], start_ms: i64, end_ms: i64) -> &[i16] {
+        let start = (start_ms * TARGET_SAMPLE_RATE / 1000).clamp(0, samples.len() as i64) as usize;
+        let end = (end_ms * TARGET_SAMPLE_RATE / 1000).clamp(start as i64, samples.len() as i64) as usize;
+        &samples[start..end]
+    }
+
+    // words grouped into windows of about window_ms, the last short one joins the one before
+    fn windows(&self, words: &[WordTimestamp]) -> Vec<(usize, usize)> {
+        let mut windows: Vec<(usize, usize)> = Vec::new();
+        let mut start = 0;
+        for (i, word) in words.iter().enumerate() {
+            if word.end_ms - words[start].start_ms >= self.embedder.config.window_ms {
+                windows.push((start, i + 1));
+                start = i + 1;
+            }
+        }
+        if start < words.len() {
+            match windows.last_mut() {
+                Some(last) => last.1 = words.len(),
+                None => windows.push((start, words.len())),
+            }
+        }
+        windows
+    }
+
+    // None for audio too short for one fbank frame, or when the model fails on it
+    fn embed_or_skip(&self, audio: &[i16]) -> Option<Vec<f32>> {
+        if audio.len() < FRAME_LENGTH {
+            return None;
+        }
+        match self.embedder.embed(audio) {
+            Ok(embedding) => Some(embedding),
+            Err(e) => {
+                warn!("failed to embed {} samples: {}", audio.len(), e);
+                None
+            }
+        }
+    }
+
+    // the speaker of the whole clip, embedded once per clip
+    fn clip_speaker(&mut self, samples: &[i16], clip_speaker: &mut Option<Option<String>>) -> Option<String> {
+        if clip_speaker.is_none() {
+            let long_enough = samples.len() as i64 * 1000 / TARGET_SAMPLE_RATE >= self.embedder.config.min_speech_ms;
+            let embedding = if long_enough { self.embed_or_skip(samples) } else { None };
+            *clip_speaker = Some(embedding.map(|embedding| self.assign(embedding)));
+        }
+        clip_speaker.clone().flatten()
+    }
+
+    // a speaker per window, windows that could not be embedded take the speaker before them
+    fn window_speakers(&mut self, samples: &[i16], words: &[WordTimestamp], windows: &[(usize, usize)]) -> Option<Vec<String>> {
+        let mut speakers: Vec<Option<String>> = Vec::with_capacity(windows.len());
+        for &(start, end) in windows {
+            let audio = Self::slice(samples, words[start].start_ms, words[end - 1].end_ms);
+            speakers.push(self.embed_or_skip(audio).map(|embedding| self.assign(embedding)));
+        }
+        let mut last = speakers.iter().flatten().next()?.clone();
+        Some(speakers.into_iter().map(|speaker| {
+            if let Some(speaker) = speaker {
+                last = speaker;
+            }
+            last.clone()
+        }).collect())
+    }
+
+    /// Set the speaker of the segments of one speech clip, `samples` is the clip and segment
+    /// times are relative to it. Splits segments at speaker changes when configured.
+    pub fn label_segments(&mut self, samples: &[i16], segments: Vec<TranscriptSegment>) -> Vec<TranscriptSegment> {
+        let mut clip_speaker: Option<Option<String>> = None;
+        let mut labelled = Vec::with_capacity(segments.len());
+        for mut segment in segments {
+            let windows = if self.embedder.config.split_on_speaker_change { self.windows(&segment.words) } else { Vec::new() };
+            let window_speakers = if windows.len() > 1 { self.window_speakers(samples, &segment.words, &windows) } else { None };
+            if let Some(window_speakers) = window_speakers {
+                // cut where the speaker changes
+                let words = std::mem::take(&mut segment.words);
+                let mut pieces: Vec<(String, usize, usize)> = Vec::new();
+                for (speaker, (start, end)) in window_speakers.into_iter().zip(windows) {
+                    match pieces.last_mut() {
+                        Some(last) if last.0 == speaker => last.2 = end,
+                        _ => pieces.push((speaker, start, end)),
+                    }
+                }
+                if pieces.len() > 1 {
+                    for (speaker, start, end) in pieces {
+                        labelled.push(TranscriptSegment {
+                            start_ms: words[start].start_ms,
+                            end_ms: words[end - 1].end_ms,
+                            text: join_words(&words[start..end]),
+                            words: words[start..end].to_vec(),
+                            speaker: Some(speaker),
+                            // of the whole segment, the pieces are translated on their own
+                            translation: None,
+                            ..segment.clone()
+                        });
+                    }
+                    continue;
+                }
+                segment.words = words;
+                segment.speaker = pieces.pop().map(|(speaker, ..)| speaker);
+                labelled.push(segment);
+                continue;
+            }
+
+            let embedding = if segment.end_ms - segment.start_ms >= self.embedder.config.min_speech_ms {
+                self.embed_or_skip(Self::slice(samples, segment.start_ms, segment.end_ms))
+            } else {
+                None
+            };
+            segment.speaker = match embedding {
+                Some(embedding) => Some(self.assign(embedding)),
+                // a clip is usually one turn, so it stands in for speech too short on its own
+                None => self.clip_speaker(samples, &mut clip_speaker),
+            };
+            labelled.push(segment);
+        }
+        labelled
+    }
+}
@@ -84,4 +84,16 @@ pub fn get_whisper_model(download_url: &str) -> Result<PathBuf, Box<dyn std::err
         download_to_temp_and_move(download_url, model_path.to_str().unwrap())?;
     }
     Ok(model_path)
-}
\ No newline at end of file
+}
+pub const DEFAULT_MODEL_DOWNLOAD_URL: &str = "https://huggingface.co/ggerganov/whisper.cpp/resolve/4496f29dabb6f37d8e6c45c3ec89ccbe66a832ea/ggml-large-v3-turbo.bin?download=true";
+
+pub fn get_model_download_url(model: Option<&str>) -> Result<&'static str, Box<dyn std::error::Error>> {
+    let model_download_url = match model {
+        None | Some("turbo") => DEFAULT_MODEL_DOWNLOAD_URL,
+        Some("base_en") => "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-base.en.bin?download=true",
+        Some("distil_small_en") => "https://huggingface.co/distil-whisper/distil-small.en/resolve/main/ggml-distil-small.en.bin?download=true",
+        Some("ggml-small-q5_1") => "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-small-q5_1.bin?download=true",
+        Some(model) => return Err(format!("unknown model: {}", model).into()),
+    };
+    Ok(model_download_url)
+}
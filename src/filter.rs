@@ -0,0 +1,295 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::Deserialize;
+
+use crate::postprocess::{is_cjk, PostProcessConfig};
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    // not saved at all
+    Drop,
+    // saved with filter_reason set
+    Flag,
+}
+
+/// `[filter]` section of the config, segments are not filtered without it.
+#[derive(Clone, Deserialize)]
+pub struct FilterConfig {
+    #[serde(default = "default_action")]
+    pub action: FilterAction,
+    // language to phrases whisper makes up on music and silence, replaces the built in lists
+    #[serde(default = "default_phrases")]
+    pub phrases: HashMap<String, Vec<String>>,
+    // the same word or phrase this many times in a row within a segment, or the same
+    // segment this many times in a row, is a loop, at least 2
+    #[serde(default = "default_max_repeats")]
+    pub max_repeats: usize,
+    #[serde(default = "default_max_chars_per_second")]
+    pub max_chars_per_second: f32,
+}
+
+fn default_action() -> FilterAction {
+    FilterAction::Drop
+}
+
+fn default_max_repeats() -> usize {
+    6
+}
+
+fn default_max_chars_per_second() -> f32 {
+    25.0
+}
+
+fn default_phrases() -> HashMap<String, Vec<String>> {
+    let mut phrases = HashMap::new();
+    phrases.insert("en".to_string(), vec![
+        "thank you for watching".to_string(),
+        "thanks for watching".to_string(),
+        "please subscribe".to_string(),
+        "subtitles by the amara.org community".to_string(),
+    ]);
+    let chinese = vec![
+        "請不吝點贊訂閱轉發打賞支持明鏡與點點欄目".to_string(),
+        "請不吝點贊訂閱".to_string(),
+        "字幕由amara.org社區提供".to_string(),
+        "謝謝收看".to_string(),
+        "多謝收看".to_string(),
+    ];
+    phrases.insert("zh".to_string(), chinese.clone());
+    phrases.insert("yue".to_string(), chinese);
+    phrases
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterReason {
+    Phrase,
+    Repetition,
+    TooLong,
+}
+
+impl FilterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterReason::Phrase => "phrase",
+            FilterReason::Repetition => "repetition",
+            FilterReason::TooLong => "too_long",
+        }
+    }
+}
+
+// lower case letters and digits only, so punctuation and spacing don't matter
+fn normalize(text: &str) -> String {
+    text.chars().filter(|c| c.is_alphanumeric()).flat_map(|c| c.to_lowercase()).collect()
+}
+
+// words of space separated scripts, single characters of cjk
+fn split_units(text: &str) -> Vec<String> {
+    let mut units = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() && (c as u32) < 0x2E80 {
+            word.extend(c.to_lowercase());
+            continue;
+        }
+        if !word.is_empty() {
+            units.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            units.push(c.to_string());
+        }
+    }
+    if !word.is_empty() {
+        units.push(word);
+    }
+    units
+}
+
+// laughter and agreement like 哈哈哈 or 係係係 repeat one character, so a run of a single
+// cjk character only loops at this many times max_repeats
+const CJK_RUN_FACTOR: usize = 5;
+
+fn is_cjk_unit(unit: &str) -> bool {
+    let mut chars = unit.chars();
+    matches!((chars.next(), chars.next()), (Some(c), None) if is_cjk(c))
+}
+
+// an n-gram repeated back to back max_repeats times
+fn has_loop(units: &[String], max_repeats: usize) -> bool {
+    let max_n = units.len() / max_repeats.max(1);
+    for n in 1..=max_n {
+        for start in 0..units.len().saturating_sub(n - 1) {
+            let gram = &units[start..start + n];
+            // counted as a run below
+            if is_cjk_unit(&gram[0]) && gram.iter().all(|unit| *unit == gram[0]) {
+                continue;
+            }
+            let mut repeats = 1;
+            let mut next = start + n;
+            while next + n <= units.len() && units[next..next + n] == *gram {
+                repeats += 1;
+                next += n;
+            }
+            if repeats >= max_repeats {
+                return true;
+            }
+        }
+    }
+    units.chunk_by(|a, b| a == b)
+        .any(|run| is_cjk_unit(&run[0]) && run.len() >= max_repeats * CJK_RUN_FACTOR)
+}
+
+/// A word or phrase of `text` looping as many times as the default max_repeats.
+pub fn has_repetition(text: &str) -> bool {
+    has_loop(&split_units(text), default_max_repeats())
+}
+
+/// Catches whisper hallucinations in the segments of one show, in order, before they are saved.
+pub struct SegmentFilter {
+    config: FilterConfig,
+    // language to normalized phrases, post processed like the text
+    phrases: HashMap<String, Vec<String>>,
+    // normalized text of the last segments
+    recent: VecDeque<String>,
+}
+
+impl SegmentFilter {
+    pub fn new(config: &FilterConfig, postprocess: &PostProcessConfig) -> Self {
+        let phrases = config.phrases.iter().map(|(language, phrases)| {
+            let mut phrases: Vec<String> = phrases.iter()
+                .map(|phrase| normalize(&postprocess.process(language, phrase)))
+                .filter(|phrase| !phrase.is_empty())
+                .collect();
+            // remove longer phrases first so their shorter prefixes don't break them up
+            phrases.sort_by_key(|phrase| std::cmp::Reverse(phrase.len()));
+            (language.clone(), phrases)
+        }).collect();
+        let mut config = config.clone();
+        // one is every segment
+        config.max_repeats = config.max_repeats.max(2);
+        Self {
+            config,
+            phrases,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn action(&self) -> FilterAction {
+        self.config.action
+    }
+
+    /// `text` as it is saved, i.e. post processed, in `language`.
+    pub fn check(&mut self, text: &str, language: &str, duration_ms: i64) -> Option<FilterReason> {
+        let normalized = normalize(text);
+
+        // this segment and the ones before it make max_repeats in a row
+        let previous = self.config.max_repeats.saturating_sub(1);
+        let repeated_segment = self.recent.len() >= previous
+            && !normalized.is_empty()
+            && self.recent.iter().all(|recent| *recent == normalized);
+        if previous > 0 {
+            if self.recent.len() >= previous {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(normalized.clone());
+        }
+
+        if !normalized.is_empty() {
+            // nothing left once the phrases are taken out
+            let mut rest = normalized.clone();
+            for phrase in self.phrases.get(language).into_iter().flatten() {
+                rest = rest.replace(phrase.as_str(), "");
+            }
+            if rest.is_empty() {
+                return Some(FilterReason::Phrase);
+            }
+        }
+
+        if repeated_segment || has_loop(&split_units(text), self.config.max_repeats) {
+            return Some(FilterReason::Repetition);
+        }
+
+        // timestamps of short segments are rough, so allow at least a second
+        let seconds = (duration_ms as f32 / 1000.0).max(1.0);
+        let chars = text.chars().filter(|c| !c.is_whitespace()).count();
+        if chars as f32 > self.config.max_chars_per_second * seconds {
+            return Some(FilterReason::TooLong);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment_filter() -> SegmentFilter {
+        let config = FilterConfig {
+            action: default_action(),
+            phrases: default_phrases(),
+            max_repeats: default_max_repeats(),
+            max_chars_per_second: default_max_chars_per_second(),
+        };
+        SegmentFilter::new(&config, &PostProcessConfig::default())
+    }
+
+    fn loops(text: &str) -> bool {
+        has_loop(&split_units(text), default_max_repeats())
+    }
+
+    #[test]
+    fn empty_text_is_not_a_repetition() {
+        assert!(!loops(""));
+        assert!(!loops(" ... "));
+    }
+
+    #[test]
+    fn word_looping_max_repeats_times() {
+        assert!(loops("go go go go go go"));
+        assert!(!loops("go go go go go"));
+        assert!(loops("I love you, I love you, I love you, I love you, I love you, I love you"));
+    }
+
+    #[test]
+    fn cjk_characters_are_units_of_their_own() {
+        assert!(loops("好啊好啊好啊好啊好啊好啊"));
+        assert!(!loops("好啊好啊好啊好啊好啊"));
+        assert!(loops("好啊OK OK OK OK OK OK"));
+    }
+
+    #[test]
+    fn laughter_is_not_a_loop() {
+        assert!(!loops("哈哈哈哈哈哈"));
+        assert!(!loops("係係係係係係，係係係係係係"));
+        assert!(!loops(&"哈".repeat(29)));
+        assert!(loops(&"哈".repeat(30)));
+    }
+
+    #[test]
+    fn too_many_chars_per_second() {
+        let mut filter = segment_filter();
+        assert_eq!(filter.check(&"a".repeat(30), "en", 1000), Some(FilterReason::TooLong));
+        assert_eq!(filter.check(&"b".repeat(30), "en", 2000), None);
+        // short segments are allowed a second
+        assert_eq!(filter.check(&"c".repeat(20), "en", 200), None);
+        // whitespace is not counted, 35 chars without and 43 with it
+        assert_eq!(filter.check("the quick brown fox jumps over the lazy dog", "en", 1500), None);
+    }
+
+    #[test]
+    fn same_segment_max_repeats_times() {
+        let mut filter = segment_filter();
+        for _ in 0..5 {
+            assert_eq!(filter.check("hello there", "en", 1000), None);
+        }
+        assert_eq!(filter.check("Hello, there!", "en", 1000), Some(FilterReason::Repetition));
+    }
+
+    #[test]
+    fn made_up_phrase() {
+        let mut filter = segment_filter();
+        assert_eq!(filter.check("Thank you for watching!", "en", 2000), Some(FilterReason::Phrase));
+        assert_eq!(filter.check("", "en", 2000), None);
+    }
+}
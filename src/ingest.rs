@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use whisper_rs::WhisperContext;
+
+use crate::config::Config;
+use crate::db::init_db_from_config;
+use crate::metrics::ShowMetrics;
+use crate::runtime_utils::get_runtime;
+use crate::decoding::DecodingConfig;
+use crate::diarization::SpeakerEmbedder;
+use crate::filter::FilterConfig;
+use crate::postprocess::PostProcessConfig;
+use crate::prompt::PromptConfig;
+use crate::vad_processor::{ingest_url, TranscriptOutput};
+
+/// Flags checked by the streaming loop of a running ingest.
+#[derive(Default)]
+pub struct IngestControl {
+    paused: AtomicBool,
+    stopped: AtomicBool,
+}
+
+impl IngestControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct IngestRequest {
+    pub show_name: String,
+    // anything ffmpeg can read, live streams are restarted when they stop
+    pub url: String,
+    // or "auto"
+    pub language: String,
+    #[serde(default)]
+    pub allowed_languages: Option<Vec<String>>,
+    #[serde(default)]
+    pub prompt: Option<PromptConfig>,
+    // also save an english translation
+    #[serde(default)]
+    pub translate: bool,
+    // the [postprocess] section of the server config when left out
+    #[serde(default)]
+    pub postprocess: Option<PostProcessConfig>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestStatus {
+    Running,
+    Paused,
+    Stopping,
+    Stopped,
+    // a url with a duration reached its end
+    Finished,
+    Failed,
+}
+
+impl IngestStatus {
+    fn is_active(&self) -> bool {
+        matches!(self, IngestStatus::Running | IngestStatus::Paused | IngestStatus::Stopping)
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct IngestInfo {
+    pub show_name: String,
+    pub url: String,
+    pub language: String,
+    pub status: IngestStatus,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+struct Ingest {
+    info: IngestInfo,
+    control: Arc<IngestControl>,
+}
+
+type IngestMap = Arc<Mutex<HashMap<String, Ingest>>>;
+
+/// Url ingests started over http by a long running server, one thread and whisper
+/// state per show, all sharing one loaded model.
+pub struct IngestManager {
+    ctx: Arc<WhisperContext>,
+    model: String,
+    n_threads: usize,
+    pool: Option<Pool<Postgres>>,
+    audio_archive_dir: Option<String>,
+    filter_config: Option<FilterConfig>,
+    decoding: DecodingConfig,
+    postprocess: PostProcessConfig,
+    speakers: Option<Arc<SpeakerEmbedder>>,
+    ingests: IngestMap,
+}
+
+impl IngestManager {
+    pub fn new(ctx: Arc<WhisperContext>, model: String, n_threads: usize, config: &Config, speakers: Option<Arc<SpeakerEmbedder>>) -> Result<Self, Box<dyn std::error::Error>> {
+        let pool = match &config.database_config {
+            Some(database_config) => Some(init_db_from_config(get_runtime(), database_config)?),
+            None => None,
+        };
+        Ok(Self {
+            ctx,
+            model,
+            n_threads,
+            pool,
+            audio_archive_dir: config.audio_archive_dir.clone(),
+            filter_config: config.filter.clone(),
+            decoding: config.decoding.clone(),
+            postprocess: config.postprocess.clone(),
+            speakers,
+            ingests: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Start transcribing a url, fails if the show already has an active ingest.
+    pub fn start(&self, request: IngestRequest) -> Result<IngestInfo, String> {
+        if request.show_name.trim().is_empty() || request.url.trim().is_empty() {
+            return Err("show_name and url are required".to_string());
+        }
+
+        let mut ingests = self.ingests.lock().unwrap();
+        if let Some(ingest) = ingests.get(&request.show_name) {
+            if ingest.info.status.is_active() {
+                return Err(format!("show {} is already being ingested", request.show_name));
+            }
+        }
+
+        let info = IngestInfo {
+            show_name: request.show_name.clone(),
+            url: request.url.clone(),
+            language: request.language.clone(),
+            status: IngestStatus::Running,
+            started_at: Utc::now(),
+            finished_at: None,
+            error: None,
+        };
+        let control = Arc::new(IngestControl::default());
+        ingests.insert(request.show_name.clone(), Ingest { info: info.clone(), control: control.clone() });
+        drop(ingests);
+
+        let ctx = self.ctx.clone();
+        let model = self.model.clone();
+        let n_threads = self.n_threads;
+        let pool = self.pool.clone();
+        let audio_archive_dir = self.audio_archive_dir.clone();
+        let filter_config = self.filter_config.clone();
+        let decoding = self.decoding.clone();
+        let postprocess = request.postprocess.clone().unwrap_or_else(|| self.postprocess.clone());
+        let speakers = self.speakers.clone();
+        let ingests = self.ingests.clone();
+        thread::spawn(move || {
+            info!("starting ingest of {} from {}", request.show_name, request.url);
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let output = TranscriptOutput {
+                    pool,
+                    audio_archive_dir: audio_archive_dir.as_ref(),
+                    filter_config: filter_config.as_ref(),
+                    postprocess: Some(&postprocess),
+                    speakers,
+                };
+                ingest_url(&ctx, &model, &request, n_threads, &decoding, output, control.clone()).map_err(|e| e.to_string())
+            }));
+            let result = match result {
+                Ok(result) => result,
+                Err(_) => Err("ingest panicked".to_string()),
+            };
+
+            // a stopped show should not fail the health check
+            ShowMetrics::for_show(&request.show_name, false);
+
+            finish_ingest(&ingests, &request.show_name, &control, result);
+        });
+
+        Ok(info)
+    }
+
+    fn update<F>(&self, show_name: &str, f: F) -> Option<IngestInfo>
+    where
+        F: FnOnce(&mut Ingest),
+    {
+        let mut ingests = self.ingests.lock().unwrap();
+        let ingest = ingests.get_mut(show_name)?;
+        f(ingest);
+        Some(ingest.info.clone())
+    }
+
+    pub fn pause(&self, show_name: &str) -> Option<IngestInfo> {
+        self.update(show_name, |ingest| {
+            if ingest.info.status == IngestStatus::Running {
+                ingest.control.paused.store(true, Ordering::Relaxed);
+                ingest.info.status = IngestStatus::Paused;
+                ShowMetrics::for_show(show_name, false);
+            }
+        })
+    }
+
+    pub fn resume(&self, show_name: &str) -> Option<IngestInfo> {
+        self.update(show_name, |ingest| {
+            if ingest.info.status == IngestStatus::Paused {
+                ingest.control.paused.store(false, Ordering::Relaxed);
+                ingest.info.status = IngestStatus::Running;
+                ShowMetrics::for_show(show_name, true);
+            }
+        })
+    }
+
+    /// The ingest stops after the chunk being read, its status becomes stopped once it has.
+    pub fn stop(&self, show_name: &str) -> Option<IngestInfo> {
+        self.update(show_name, |ingest| {
+            if matches!(ingest.info.status, IngestStatus::Running | IngestStatus::Paused) {
+                ingest.control.stopped.store(true, Ordering::Relaxed);
+                ingest.info.status = IngestStatus::Stopping;
+            }
+        })
+    }
+
+    pub fn get(&self, show_name: &str) -> Option<IngestInfo> {
+        self.ingests.lock().unwrap().get(show_name).map(|ingest| ingest.info.clone())
+    }
+
+    pub fn list(&self) -> Vec<IngestInfo> {
+        let mut list: Vec<IngestInfo> = self.ingests.lock().unwrap().values().map(|ingest| ingest.info.clone()).collect();
+        list.sort_by(|a, b| a.show_name.cmp(&b.show_name));
+        list
+    }
+}
+
+// the status an ingest ends with, after which the show can be started again
+fn finish_ingest(ingests: &IngestMap, show_name: &str, control: &IngestControl, result: Result<(), String>) {
+    if let Some(ingest) = ingests.lock().unwrap().get_mut(show_name) {
+        ingest.info.finished_at = Some(Utc::now());
+        match result {
+            Ok(()) if control.is_stopped() => ingest.info.status = IngestStatus::Stopped,
+            Ok(()) => ingest.info.status = IngestStatus::Finished,
+            Err(e) => {
+                error!("ingest of {} failed: {}", show_name, e);
+                ingest.info.status = IngestStatus::Failed;
+                ingest.info.error = Some(e);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel::bounded;
+
+    use crate::streaming::{end_stream_on_error, forward_chunks, Segment};
+
+    fn running_ingest(show_name: &str) -> (IngestMap, Arc<IngestControl>) {
+        let control = Arc::new(IngestControl::default());
+        let info = IngestInfo {
+            show_name: show_name.to_string(),
+            url: "http://example.com/live".to_string(),
+            language: "yue".to_string(),
+            status: IngestStatus::Running,
+            started_at: Utc::now(),
+            finished_at: None,
+            error: None,
+        };
+        let ingests: IngestMap = Arc::new(Mutex::new(HashMap::new()));
+        ingests.lock().unwrap().insert(show_name.to_string(), Ingest { info, control: control.clone() });
+        (ingests, control)
+    }
+
+    #[test]
+    fn live_stream_filling_the_channel_fails_the_ingest() {
+        let (ingests, control) = running_ingest("live");
+        let (tx, rx) = bounded::<Option<Segment>>(4);
+        let mut result = Ok(());
+        thread::scope(|s| {
+            // stands in for the vad thread, slower than the stream and only done at its end
+            s.spawn(|| {
+                for segment in &rx {
+                    if segment.is_none() {
+                        break;
+                    }
+                    thread::sleep(std::time::Duration::from_millis(10));
+                }
+            });
+            let streamed = forward_chunks(&mut std::io::repeat(0), 1024, &tx, true, Some(&*control)).map(|_| ());
+            result = end_stream_on_error(&tx, streamed);
+        });
+        finish_ingest(&ingests, "live", &control, result);
+
+        let info = ingests.lock().unwrap().get("live").unwrap().info.clone();
+        assert!(info.status == IngestStatus::Failed);
+        assert!(info.error.unwrap().contains("Channel is full"));
+        assert!(info.finished_at.is_some());
+        // so it can be started again
+        assert!(!info.status.is_active());
+    }
+
+    #[test]
+    fn stopped_ingest() {
+        let (ingests, control) = running_ingest("show");
+        control.stopped.store(true, Ordering::Relaxed);
+        let (tx, _rx) = bounded::<Option<Segment>>(4);
+        let streamed = forward_chunks(&mut std::io::repeat(0), 1024, &tx, true, Some(&*control));
+        assert!(matches!(streamed, Ok(true)));
+        finish_ingest(&ingests, "show", &control, Ok(()));
+        let status = ingests.lock().unwrap().get("show").unwrap().info.status;
+        assert!(status == IngestStatus::Stopped);
+    }
+}
@@ -0,0 +1,312 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use log::{error, info, warn};
+use sqlx::{Pool, Postgres, Row};
+use whisper_rs::{WhisperContext, WhisperState};
+
+use crate::config::Config;
+use crate::db::{init_db_from_config, insert_transcript, NewTranscript};
+use crate::decoding::DecodingConfig;
+use crate::diarization::{Diarizer, SpeakerEmbedder};
+use crate::language::LanguageDetector;
+use crate::postprocess::PostProcessConfig;
+use crate::download_utils::get_model_download_url;
+use crate::filter::{FilterAction, FilterConfig, SegmentFilter};
+use crate::prompt::ShowPrompt;
+use crate::runtime_utils::get_runtime;
+use crate::shows::register_show;
+use crate::vad_processor::{build_params, load_whisper_context, transcribe_file, words_for_db, ClipOptions, TranscriptSegment};
+
+pub struct NewJob {
+    // url or local path, anything ffmpeg can read
+    pub source: String,
+    pub show_name: String,
+    pub model: String,
+    pub language: String,
+    pub priority: i32,
+    pub max_attempts: i32,
+    // start time of the recording, transcripts are timestamped relative to it
+    pub recorded_at: Option<NaiveDateTime>,
+}
+
+pub struct WorkerOptions {
+    pub worker_id: String,
+    pub n_threads: usize,
+    pub poll_interval: Duration,
+    pub lease_seconds: i64,
+}
+
+struct ClaimedJob {
+    id: i64,
+    source: String,
+    show_name: String,
+    model: String,
+    language: String,
+    recorded_at: Option<NaiveDateTime>,
+}
+
+struct LoadedModel {
+    model: String,
+    ctx: WhisperContext,
+    state: WhisperState,
+}
+
+pub fn enqueue_job(config: &Config, job: &NewJob) -> Result<i64, Box<dyn std::error::Error>> {
+    let rt = get_runtime();
+    let database_config = config.database_config.as_ref().ok_or("database_config is required for the job queue")?;
+    let pool = init_db_from_config(rt, database_config)?;
+
+    // fail early instead of in the worker
+    get_model_download_url(Some(job.model.as_str()))?;
+
+    rt.block_on(async {
+        let row = sqlx::query(r#"INSERT INTO transcription_jobs (source, show_name, model, language, priority, max_attempts, recorded_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id"#)
+        .bind(job.source.as_str())
+        .bind(job.show_name.as_str())
+        .bind(job.model.as_str())
+        .bind(job.language.as_str())
+        .bind(job.priority)
+        .bind(job.max_attempts)
+        .bind(job.recorded_at)
+        .fetch_one(&pool).await?;
+        Ok(row.try_get("id")?)
+    })
+}
+
+async fn claim_job(pool: &Pool<Postgres>, worker_id: &str, lease_seconds: i64) -> Result<Option<ClaimedJob>, Box<dyn std::error::Error>> {
+    // jobs whose worker died on the last attempt will never be picked up again
+    sqlx::query(r#"UPDATE transcription_jobs
+        SET status = 'failed', lease_owner = NULL, lease_expires_at = NULL,
+            last_error = coalesce(last_error, 'lease expired'), finished_at = (now() at time zone 'utc')
+        WHERE status = 'running' AND lease_expires_at < (now() at time zone 'utc') AND attempts >= max_attempts"#)
+    .execute(pool).await?;
+
+    // an expired lease means the worker holding it has died, so the job can be claimed again
+    let row = sqlx::query(r#"UPDATE transcription_jobs
+        SET status = 'running', attempts = attempts + 1, lease_owner = $1,
+            lease_expires_at = (now() at time zone 'utc') + make_interval(secs => $2),
+            started_at = (now() at time zone 'utc')
+        WHERE id = (
+            SELECT id FROM transcription_jobs
+            WHERE (status = 'queued' OR (status = 'running' AND lease_expires_at < (now() at time zone 'utc')))
+            AND attempts < max_attempts
+            ORDER BY priority DESC, id
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED)
+        RETURNING id, source, show_name, model, language, recorded_at"#)
+    .bind(worker_id)
+    .bind(lease_seconds as f64)
+    .fetch_optional(pool).await?;
+
+    match row {
+        Some(row) => Ok(Some(ClaimedJob {
+            id: row.try_get("id")?,
+            source: row.try_get("source")?,
+            show_name: row.try_get("show_name")?,
+            model: row.try_get("model")?,
+            language: row.try_get("language")?,
+            recorded_at: row.try_get("recorded_at")?,
+        })),
+        None => Ok(None),
+    }
+}
+
+async fn renew_lease(pool: &Pool<Postgres>, job_id: i64, worker_id: &str, lease_seconds: i64) -> Result<(), Box<dyn std::error::Error>> {
+    sqlx::query(r#"UPDATE transcription_jobs
+        SET lease_expires_at = (now() at time zone 'utc') + make_interval(secs => $3)
+        WHERE id = $1 AND lease_owner = $2 AND status = 'running'"#)
+    .bind(job_id)
+    .bind(worker_id)
+    .bind(lease_seconds as f64)
+    .execute(pool).await?;
+    Ok(())
+}
+
+async fn complete_job(pool: &Pool<Postgres>, job: &ClaimedJob, worker_id: &str, segments: Vec<TranscriptSegment>, filter_config: Option<&FilterConfig>, postprocess: &PostProcessConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let base_timestamp = match job.recorded_at {
+        Some(recorded_at) => recorded_at.and_utc(),
+        None => Utc::now(),
+    };
+
+    let mut filter = filter_config.map(|filter_config| SegmentFilter::new(filter_config, postprocess));
+
+    // all or nothing, so a retried job does not leave duplicate transcripts behind
+    let mut tx = pool.begin().await?;
+    let completed = sqlx::query(r#"UPDATE transcription_jobs
+        SET status = 'completed', lease_owner = NULL, lease_expires_at = NULL, last_error = NULL,
+            finished_at = (now() at time zone 'utc')
+        WHERE id = $1 AND lease_owner = $2 AND status = 'running'"#)
+    .bind(job.id)
+    .bind(worker_id)
+    .execute(&mut *tx).await?;
+    if completed.rows_affected() == 0 {
+        // the lease expired and another worker has the job now, its transcripts are the ones kept
+        warn!("lost the lease of job {}, not saving its transcripts", job.id);
+        tx.rollback().await?;
+        return Ok(());
+    }
+    register_show(&mut *tx, job.show_name.as_str(), job.language.as_str(), job.model.as_str()).await?;
+    for segment in segments {
+        let timestamp = Utc.timestamp_millis_opt(base_timestamp.timestamp_millis() + segment.start_ms).unwrap();
+        let language = segment.language.clone().unwrap_or_else(|| job.language.clone());
+        let words = words_for_db(postprocess, &language, &segment);
+        let text = postprocess.process(&language, &segment.text);
+        let filter_reason = match &mut filter {
+            Some(filter) => match filter.check(&text, &language, segment.end_ms - segment.start_ms) {
+                Some(_) if filter.action() == FilterAction::Drop => continue,
+                reason => reason.map(|reason| reason.as_str()),
+            },
+            None => None,
+        };
+        insert_transcript(&mut *tx, &NewTranscript {
+            show_name: job.show_name.as_str(),
+            timestamp,
+            content: &text,
+            raw_content: &segment.text,
+            duration_ms: segment.end_ms - segment.start_ms,
+            audio: None,
+            words: &words,
+            confidence: &segment.confidence,
+            filter_reason,
+            translation: segment.translation.as_deref(),
+            language: &language,
+            language_probability: segment.language_probability,
+            speaker: segment.speaker.as_deref(),
+        }).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn fail_job(pool: &Pool<Postgres>, job_id: i64, worker_id: &str, error: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let failed = sqlx::query(r#"UPDATE transcription_jobs
+        SET status = CASE WHEN attempts >= max_attempts THEN 'failed' ELSE 'queued' END,
+            lease_owner = NULL, lease_expires_at = NULL, last_error = $3,
+            finished_at = CASE WHEN attempts >= max_attempts THEN (now() at time zone 'utc') ELSE NULL END
+        WHERE id = $1 AND lease_owner = $2 AND status = 'running'"#)
+    .bind(job_id)
+    .bind(worker_id)
+    .bind(error)
+    .execute(pool).await?;
+    if failed.rows_affected() == 0 {
+        // another worker has taken the job over, leave it running
+        warn!("lost the lease of job {}, not requeueing it", job_id);
+    }
+    Ok(())
+}
+
+fn run_job(job: &ClaimedJob, loaded: &mut Option<LoadedModel>, n_threads: usize, decoding: &DecodingConfig, prompt: Option<&str>, translate: bool, speakers: Option<&Arc<SpeakerEmbedder>>) -> Result<Vec<TranscriptSegment>, String> {
+    if loaded.as_ref().map(|loaded| loaded.model != job.model).unwrap_or(true) {
+        // free the previous model before loading another one
+        *loaded = None;
+        let download_url = get_model_download_url(Some(job.model.as_str())).map_err(|e| e.to_string())?;
+        let ctx = load_whisper_context(download_url).map_err(|e| e.to_string())?;
+        let state = ctx.create_state().map_err(|e| e.to_string())?;
+        *loaded = Some(LoadedModel { model: job.model.clone(), ctx, state });
+    }
+    let LoadedModel { ctx, state, .. } = loaded.as_mut().unwrap();
+
+    let mut params = build_params(&job.language, n_threads, decoding);
+    if let Some(prompt) = prompt {
+        params.set_initial_prompt(prompt);
+    }
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let detector = LanguageDetector::from_config(&job.language, None, n_threads).map_err(|e| e.to_string())?;
+        // speakers are told apart within the file only
+        let diarizer = speakers.cloned().map(|speakers| Mutex::new(Diarizer::new(speakers)));
+        let options = ClipOptions {
+            n_threads,
+            translate,
+            detector: detector.as_ref(),
+            diarizer: diarizer.as_ref(),
+        };
+        transcribe_file(ctx, state, &params, &job.source, options, |_| {}).map_err(|e| e.to_string())
+    }));
+
+    match result {
+        Ok(result) => result,
+        Err(_) => {
+            // the state may be left in a bad state after a panic
+            *loaded = None;
+            Err("transcription panicked".to_string())
+        }
+    }
+}
+
+/// Claim and transcribe queued jobs until killed, saving transcripts to the database.
+pub fn run_queue_worker(config: &Config, options: &WorkerOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let rt = get_runtime();
+    let database_config = config.database_config.as_ref().ok_or("database_config is required for the job queue")?;
+    let pool = init_db_from_config(rt, database_config)?;
+
+    let mut loaded: Option<LoadedModel> = None;
+    let mut prompt = config.prompt.as_ref().map(ShowPrompt::new);
+    let speakers = config.diarization.as_ref().map(SpeakerEmbedder::new).transpose()?.map(Arc::new);
+
+    info!("worker {} waiting for jobs", options.worker_id);
+    loop {
+        let job = match rt.block_on(claim_job(&pool, &options.worker_id, options.lease_seconds)) {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                sleep(options.poll_interval);
+                continue;
+            },
+            Err(e) => {
+                error!("failed to claim job: {}", e);
+                sleep(options.poll_interval);
+                continue;
+            },
+        };
+
+        info!("worker {} claimed job {} {}", options.worker_id, job.id, job.source);
+
+        // the prompt of the config is for its own show only
+        let job_prompt = match &mut prompt {
+            Some(prompt) if job.show_name == config.show_name => prompt.current(),
+            _ => None,
+        };
+
+        let finished = AtomicBool::new(false);
+        let result = thread::scope(|s| {
+            // keep the lease while transcribing so other workers don't take the job over
+            s.spawn(|| {
+                let renew_every = Duration::from_secs((options.lease_seconds / 3).max(1) as u64);
+                let mut waited = Duration::ZERO;
+                while !finished.load(Ordering::Relaxed) {
+                    sleep(Duration::from_millis(200));
+                    waited += Duration::from_millis(200);
+                    if waited >= renew_every {
+                        waited = Duration::ZERO;
+                        if let Err(e) = rt.block_on(renew_lease(&pool, job.id, &options.worker_id, options.lease_seconds)) {
+                            warn!("failed to renew lease for job {}: {}", job.id, e);
+                        }
+                    }
+                }
+            });
+            let result = run_job(&job, &mut loaded, options.n_threads, &config.decoding, job_prompt.as_deref(), config.translate, speakers.as_ref());
+            finished.store(true, Ordering::Relaxed);
+            result
+        });
+
+        let saved = match result {
+            Ok(segments) => {
+                info!("job {} transcribed {} segments", job.id, segments.len());
+                rt.block_on(complete_job(&pool, &job, &options.worker_id, segments, config.filter.as_ref(), &config.postprocess))
+            },
+            Err(e) => {
+                error!("job {} failed: {}", job.id, e);
+                rt.block_on(fail_job(&pool, job.id, &options.worker_id, &e))
+            },
+        };
+        if let Err(e) = saved {
+            // the lease will expire and the job will be retried
+            error!("failed to save result of job {}: {}", job.id, e);
+        }
+    }
+}
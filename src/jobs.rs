@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, Utc};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{error, info};
+use serde::Serialize;
+use tempfile::TempPath;
+use tokio::sync::oneshot;
+use whisper_rs::WhisperContext;
+
+use crate::decoding::DecodingConfig;
+use crate::diarization::{Diarizer, SpeakerEmbedder};
+use crate::language::LanguageDetector;
+use crate::postprocess::PostProcessConfig;
+use crate::vad_processor::{build_params, transcribe_file, ClipOptions, TranscriptSegment};
+
+// finished jobs are forgotten after this long
+const JOB_RETENTION_HOURS: i64 = 24;
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub file_name: String,
+    pub language: String,
+    // segments also have an english translation
+    pub translate: bool,
+    pub status: JobStatus,
+    // 0.0 to 1.0
+    pub progress: f32,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+struct Job {
+    info: JobInfo,
+    segments: Vec<TranscriptSegment>,
+}
+
+struct JobRequest {
+    id: String,
+    language: String,
+    translate: bool,
+    // the uploaded file, deleted when dropped after transcribing
+    file: TempPath,
+    // set when the caller waits for the result instead of polling
+    done: Option<oneshot::Sender<Result<Vec<TranscriptSegment>, String>>>,
+}
+
+type JobMap = Arc<Mutex<HashMap<String, Job>>>;
+
+/// In memory queue of uploaded files, transcribed one at a time by a worker thread
+/// that owns its own whisper state.
+///
+/// Files someone waits for, e.g. from the openai endpoint, have a worker and state of their own
+/// so they don't wait behind long uploads.
+pub struct JobManager {
+    jobs: JobMap,
+    tx: Sender<JobRequest>,
+    waiting_tx: Sender<JobRequest>,
+}
+
+impl JobManager {
+    /// `speakers` labels the speakers of every file, told apart within the file.
+    pub fn new(ctx: Arc<WhisperContext>, n_threads: usize, decoding: DecodingConfig, postprocess: PostProcessConfig, speakers: Option<Arc<SpeakerEmbedder>>) -> Self {
+        let jobs: JobMap = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, rx) = unbounded::<JobRequest>();
+        let (waiting_tx, waiting_rx) = unbounded::<JobRequest>();
+        let (ctx2, decoding2, postprocess2, speakers2, jobs2) = (ctx.clone(), decoding.clone(), postprocess.clone(), speakers.clone(), jobs.clone());
+        thread::spawn(move || run_worker(ctx2, n_threads, decoding2, postprocess2, speakers2, jobs2, rx));
+        let jobs2 = jobs.clone();
+        thread::spawn(move || run_worker(ctx, n_threads, decoding, postprocess, speakers, jobs2, waiting_rx));
+        Self { jobs, tx, waiting_tx }
+    }
+
+    pub fn submit(&self, file_name: String, language: String, translate: bool, file: TempPath) -> JobInfo {
+        self.enqueue(file_name, language, translate, file, None)
+    }
+
+    /// Queue a file like `submit` and wait for its segments.
+    pub async fn transcribe(&self, file_name: String, language: String, file: TempPath) -> Result<Vec<TranscriptSegment>, String> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.enqueue(file_name, language, false, file, Some(done_tx));
+        done_rx.await.map_err(|_| "transcription worker has stopped".to_string())?
+    }
+
+    fn enqueue(&self, file_name: String, language: String, translate: bool, file: TempPath, done: Option<oneshot::Sender<Result<Vec<TranscriptSegment>, String>>>) -> JobInfo {
+        let id = format!("{:016x}", rand::random::<u64>());
+        let info = JobInfo {
+            id: id.clone(),
+            file_name,
+            language: language.clone(),
+            translate,
+            status: JobStatus::Queued,
+            progress: 0.0,
+            created_at: Utc::now(),
+            finished_at: None,
+            error: None,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        prune_jobs(&mut jobs);
+        jobs.insert(id.clone(), Job { info: info.clone(), segments: Vec::new() });
+        drop(jobs);
+
+        let tx = if done.is_some() { &self.waiting_tx } else { &self.tx };
+        tx.send(JobRequest { id, language, translate, file, done }).unwrap();
+        info
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobInfo> {
+        let mut jobs = self.jobs.lock().unwrap();
+        prune_jobs(&mut jobs);
+        jobs.get(id).map(|job| job.info.clone())
+    }
+
+    pub fn result(&self, id: &str) -> Option<(JobInfo, Vec<TranscriptSegment>)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        prune_jobs(&mut jobs);
+        jobs.get(id).map(|job| (job.info.clone(), job.segments.clone()))
+    }
+}
+
+// on every enqueue and read, so an idle server doesn't keep old results either
+fn prune_jobs(jobs: &mut HashMap<String, Job>) {
+    let cutoff = Utc::now() - chrono::Duration::hours(JOB_RETENTION_HOURS);
+    jobs.retain(|_, job| job.info.finished_at.map(|ts| ts > cutoff).unwrap_or(true));
+}
+
+fn update_job<F>(jobs: &JobMap, id: &str, f: F)
+where
+    F: FnOnce(&mut Job),
+{
+    if let Some(job) = jobs.lock().unwrap().get_mut(id) {
+        f(job);
+    }
+}
+
+fn run_worker(ctx: Arc<WhisperContext>, n_threads: usize, decoding: DecodingConfig, postprocess: PostProcessConfig, speakers: Option<Arc<SpeakerEmbedder>>, jobs: JobMap, rx: Receiver<JobRequest>) {
+    let mut state = ctx.create_state().expect("failed to create state");
+
+    for request in rx {
+        info!("transcribing job {}", request.id);
+        update_job(&jobs, &request.id, |job| job.info.status = JobStatus::Running);
+
+        let params = build_params(&request.language, n_threads, &decoding);
+        let input_file = request.file.to_string_lossy().to_string();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let detector = LanguageDetector::from_config(&request.language, None, n_threads).map_err(|e| e.to_string())?;
+            let diarizer = speakers.clone().map(|speakers| Mutex::new(Diarizer::new(speakers)));
+            let options = ClipOptions {
+                n_threads,
+                translate: request.translate,
+                detector: detector.as_ref(),
+                diarizer: diarizer.as_ref(),
+            };
+            transcribe_file(&ctx, &mut state, &params, &input_file, options, |progress| {
+                update_job(&jobs, &request.id, |job| job.info.progress = progress);
+            }).map_err(|e| e.to_string())
+        }));
+
+        let result = match result {
+            Ok(result) => result,
+            Err(_) => {
+                // the state may be left in a bad state after a panic
+                state = ctx.create_state().expect("failed to create state");
+                Err("transcription panicked".to_string())
+            }
+        };
+        let result = result.map(|mut segments| {
+            for segment in segments.iter_mut() {
+                let language = segment.language.clone().unwrap_or_else(|| request.language.clone());
+                postprocess.process_segment(&language, segment);
+            }
+            segments
+        });
+
+        if let Some(done) = request.done {
+            // the caller may have gone away already
+            let _ = done.send(result.clone());
+        }
+
+        update_job(&jobs, &request.id, |job| {
+            job.info.finished_at = Some(Utc::now());
+            match result {
+                Ok(segments) => {
+                    job.info.status = JobStatus::Completed;
+                    job.info.progress = 1.0;
+                    job.segments = segments;
+                },
+                Err(e) => {
+                    error!("job {} failed: {}", request.id, e);
+                    job.info.status = JobStatus::Failed;
+                    job.info.error = Some(e);
+                },
+            }
+        });
+    }
+}
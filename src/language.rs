@@ -0,0 +1,47 @@
+use whisper_rs::WhisperState;
+
+/// `language` of the config that has whisper pick the language of every speech clip.
+pub const AUTO_LANGUAGE: &str = "auto";
+
+/// Picks the language of each speech clip, among the allowed languages when there are any.
+pub struct LanguageDetector {
+    // whisper language ids, empty for all of them
+    allowed: Vec<i32>,
+    n_threads: usize,
+}
+
+fn language_id(language: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    whisper_rs::get_lang_id(language).ok_or_else(|| format!("unknown language: {}", language).into())
+}
+
+impl LanguageDetector {
+    /// None for a single fixed language, `allowed_languages` also turns detection on.
+    pub fn from_config(language: &str, allowed_languages: Option<&[String]>, n_threads: usize) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let allowed_languages = allowed_languages.unwrap_or_default();
+        if language != AUTO_LANGUAGE && allowed_languages.is_empty() {
+            language_id(language)?;
+            return Ok(None);
+        }
+        let allowed = allowed_languages.iter()
+            .map(|language| language_id(language))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(Self { allowed, n_threads }))
+    }
+
+    /// The most likely language of `audio` and its probability, encodes the first 30 seconds.
+    pub fn detect(&self, state: &mut WhisperState, audio: &[f32]) -> Result<(&'static str, f32), Box<dyn std::error::Error>> {
+        state.pcm_to_mel(audio, self.n_threads)?;
+        let (_, probs) = state.lang_detect(0, self.n_threads)?;
+        let candidates: Vec<i32> = if self.allowed.is_empty() {
+            (0..probs.len() as i32).collect()
+        } else {
+            self.allowed.clone()
+        };
+        let (id, probability) = candidates.into_iter()
+            .filter_map(|id| probs.get(id as usize).map(|p| (id, *p)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .ok_or("no language probabilities")?;
+        let language = whisper_rs::get_lang_str(id).ok_or_else(|| format!("unknown language id {}", id))?;
+        Ok((language, probability))
+    }
+}
@@ -11,11 +11,29 @@ pub mod download_utils;
 pub mod key_ring_utils;
 //pub mod log_builder;
 pub mod utils;
+pub mod search;
+pub mod jobs;
+pub mod job_queue;
+pub mod subtitle;
+pub mod metrics;
+pub mod audio_archive;
+pub mod corrections;
+pub mod shows;
+pub mod ingest;
+pub mod wyoming;
+pub mod filter;
+pub mod prompt;
+pub mod decoding;
+pub mod language;
+pub mod postprocess;
+pub mod diarization;
+mod db;
+mod audio_decoder;
 mod vad;
 mod record_audio;
 mod runtime_utils;
 
-mod web;
+pub mod web;
 //mod record_output;
 
 pub fn convert_file_to_wave(input_file: &str, target_sample_rate: i32) -> Result<Vec<i16>, Box<dyn std::error::Error>> {
@@ -4,15 +4,27 @@ use std::process;
 // Trait for extending std::path::Path
 use path_slash::PathExt as _;
 
+use whisper_transcribe_rs::download_utils::{get_filename_from_url, get_model_download_url};
 use whisper_transcribe_rs::key_ring_utils;
+use whisper_transcribe_rs::search::{parse_timestamp, search_from_config, SearchParams};
 use whisper_transcribe_rs::vad_processor::stream_to_file;
-use whisper_transcribe_rs::vad_processor::transcribe_url;
+use whisper_transcribe_rs::vad_processor::{default_num_transcribe_threads, load_whisper_context, transcribe_url};
+use whisper_transcribe_rs::jobs::JobManager;
+use whisper_transcribe_rs::diarization::SpeakerEmbedder;
+use whisper_transcribe_rs::ingest::IngestManager;
+use whisper_transcribe_rs::corrections::export_corrections_from_config;
+use whisper_transcribe_rs::job_queue::{enqueue_job, run_queue_worker, NewJob, WorkerOptions};
 use whisper_transcribe_rs::config::Config;
 use whisper_transcribe_rs::utils::get_config_dir;
+use whisper_transcribe_rs::web::{serve_transcripts, DEFAULT_WEB_PORT};
+use whisper_transcribe_rs::wyoming::serve_wyoming;
 use std::io::Write;
 //use whisper_transcribe_rs::log_builder::MyLoggerBuilder;
 
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
 
@@ -45,11 +57,145 @@ enum Commands {
 
         #[arg(short, long)]
         num_transcribe_threads: Option<usize>,
+
+        #[arg(long, default_value_t = 1, help = "speech clips transcribed at once, each with its own whisper state and num_transcribe_threads")]
+        num_workers: usize,
+
+        #[arg(long, help = "also serve the web page and transcript apis on this port while transcribing")]
+        serve_port: Option<u16>,
+
+        #[arg(long, default_value = "127.0.0.1")]
+        serve_host: IpAddr,
+
+        #[arg(long, help = "serve only /metrics and /healthz on this port, also bound to --serve-host")]
+        metrics_port: Option<u16>,
     },
     #[command(about = "split audio with vad and save to file")]
     SaveToFile,
     #[command(about = "set database password from config file")]
-    SetDbPassword
+    SetDbPassword,
+    #[command(about = "serve the web page and transcript apis from the database without transcribing")]
+    Serve {
+
+        #[arg(short, long, default_value_t = DEFAULT_WEB_PORT)]
+        port: u16,
+
+        #[arg(long, default_value = "127.0.0.1")]
+        host: IpAddr,
+
+        #[arg(long, help = "accept uploaded audio files for transcription under /api/jobs")]
+        jobs: bool,
+
+        #[arg(long, help = "start, pause and stop url ingests under /api/ingests")]
+        ingest: bool,
+
+        #[arg(short, long)]
+        model: Option<String>,
+
+        #[arg(short, long)]
+        num_transcribe_threads: Option<usize>,
+    },
+    #[command(about = "full text search across stored transcripts")]
+    Search {
+
+        query: String,
+
+        #[arg(short, long, help = "only search this show")]
+        show_name: Option<String>,
+
+        #[arg(long, help = "rfc3339 timestamp, e.g. 2024-10-01T00:00:00+08:00")]
+        from: Option<String>,
+
+        #[arg(long, help = "rfc3339 timestamp, e.g. 2024-10-08T00:00:00+08:00")]
+        to: Option<String>,
+
+        #[arg(short, long, default_value_t = 20)]
+        limit: i64,
+    },
+    #[command(about = "export corrected transcripts with their original text as jsonl")]
+    ExportCorrections {
+
+        #[arg(short, long)]
+        show_name: Option<String>,
+
+        #[arg(long, help = "rfc3339 timestamp, e.g. 2024-10-01T00:00:00+08:00")]
+        from: Option<String>,
+
+        #[arg(long, help = "rfc3339 timestamp, e.g. 2024-10-08T00:00:00+08:00")]
+        to: Option<String>,
+    },
+    #[command(about = "queue a url or file for transcription by a worker")]
+    Enqueue {
+
+        source: String,
+
+        #[arg(short, long, help = "defaults to show_name from the config file")]
+        show_name: Option<String>,
+
+        #[arg(short, long)]
+        model: Option<String>,
+
+        #[arg(short, long, help = "defaults to language from the config file")]
+        language: Option<String>,
+
+        #[arg(short, long, default_value_t = 0, help = "higher priority jobs are claimed first")]
+        priority: i32,
+
+        #[arg(long, default_value_t = 3)]
+        max_attempts: i32,
+
+        #[arg(long, help = "rfc3339 start time of the recording, transcripts are timestamped from it")]
+        recorded_at: Option<String>,
+    },
+    #[command(about = "claim and transcribe queued jobs until killed")]
+    Work {
+
+        #[arg(short, long, help = "defaults to worker-<pid>")]
+        worker_id: Option<String>,
+
+        #[arg(short, long)]
+        num_transcribe_threads: Option<usize>,
+
+        #[arg(long, default_value_t = 5)]
+        poll_seconds: u64,
+
+        #[arg(long, default_value_t = 300, help = "a job held by a worker that stops renewing its lease for this long is retried")]
+        lease_seconds: i64,
+    },
+    #[command(about = "serve speech to text over the wyoming protocol for home assistant, configured by the [wyoming] section")]
+    Wyoming {
+
+        #[arg(short, long)]
+        num_transcribe_threads: Option<usize>,
+    },
+}
+
+fn init_logging(log_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let config_folder = get_config_dir()?;
+    eprintln!("config_folder: {}", config_folder.to_str().unwrap());
+
+    let log_dir = config_folder.join("logs");
+    fs::create_dir_all(&log_dir)?;
+
+    let log_path = log_dir.join(format!("{}.log",log_name));
+    let log_path_my_app = log_dir.join(format!("{}_streaming.log",log_name));
+
+    let template = include_str!("log4rs.yaml");
+
+    // Replace the placeholder with the actual log path
+    let config_str = template.replace("{{log_path}}", &log_path.to_slash().unwrap())
+                                     .replace("{{log_path_my_app}}", &log_path_my_app.to_slash().unwrap());
+
+
+    // either this or log4s but not both
+    // Initialize tracing with a subscriber that respects the log level and formats.
+    // tracing_subscriber::fmt()
+    // .with_env_filter(EnvFilter::new("debug")) // Set default log level to debug
+    // .init();
+
+    let config_log = serde_yaml::from_str(config_str.as_str()).unwrap();
+    log4rs::init_raw_config(config_log).unwrap();
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -57,61 +203,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
     
     let config: Config = toml::from_str(fs::read_to_string(cli.config_file)?.as_str()).unwrap();
+    if let Err(e) = config.decoding.validate() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
     let subcommand = cli.command;
     match subcommand {
-        Commands::Transcribe{ model, num_transcribe_threads} => {
-
-            let default_download_url = "https://huggingface.co/ggerganov/whisper.cpp/resolve/4496f29dabb6f37d8e6c45c3ec89ccbe66a832ea/ggml-large-v3-turbo.bin?download=true";
-
-            let mut model_download_url = default_download_url;
-            if let Some(model) = model {
-                match model.as_str() {
-                    "turbo"=>{
-                        model_download_url = default_download_url;
-                    },
-                    "base_en" =>{
-                        model_download_url = "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-base.en.bin?download=true";
-                    },
-                    "distil_small_en" =>{
-                        model_download_url = "https://huggingface.co/distil-whisper/distil-small.en/resolve/main/ggml-distil-small.en.bin?download=true";
-                    },
-                    "ggml-small-q5_1" =>{
-                        model_download_url = "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-small-q5_1.bin?download=true";
-                    },
-                    _=>{
-                        eprintln!("unknown model: {}", model);
-                        process::exit(1);
-                    }
+        Commands::Transcribe{ model, num_transcribe_threads, num_workers, serve_port, serve_host, metrics_port} => {
+
+            let model_download_url = match get_model_download_url(model.as_deref()) {
+                Ok(url) => url,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
                 }
-            }
+            };
         
-            let config_folder = get_config_dir()?;
-            eprintln!("config_folder: {}", config_folder.to_str().unwrap());
-
-            let log_dir = config_folder.join("logs");
-            fs::create_dir_all(&log_dir)?;
-
-            let log_path = log_dir.join(format!("{}.log",config.show_name));
-            let log_path_my_app = log_dir.join(format!("{}_streaming.log",config.show_name));
-
-            let template = include_str!("log4rs.yaml");
-
-            // Replace the placeholder with the actual log path
-            let config_str = template.replace("{{log_path}}", &log_path.to_slash().unwrap())
-                                             .replace("{{log_path_my_app}}", &log_path_my_app.to_slash().unwrap());
-
-
-            // either this or log4s but not both
-            // Initialize tracing with a subscriber that respects the log level and formats.
-            // tracing_subscriber::fmt()
-            // .with_env_filter(EnvFilter::new("debug")) // Set default log level to debug
-            // .init();
-
-            let config_log = serde_yaml::from_str(config_str.as_str()).unwrap();
-            log4rs::init_raw_config(config_log).unwrap();
+            init_logging(&config.show_name)?;
         
             whisper_rs::install_whisper_log_trampoline();
-            transcribe_url(config,num_transcribe_threads,model_download_url)?;
+            let serve_addr = serve_port.map(|port| SocketAddr::new(serve_host, port));
+            let metrics_addr = metrics_port.map(|port| SocketAddr::new(serve_host, port));
+            transcribe_url(config,num_transcribe_threads,num_workers,model_download_url,serve_addr,metrics_addr)?;
         
         },
         Commands::SaveToFile => {
@@ -129,6 +242,79 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             key_ring_utils::set_password(&database_password_key, password)?;
             //return Ok(());
         },
+        Commands::Serve { port, host, jobs, ingest, model, num_transcribe_threads } => {
+            let n_threads = num_transcribe_threads.unwrap_or_else(default_num_transcribe_threads);
+            let model_download_url = get_model_download_url(model.as_deref())?;
+            // jobs and ingests share one loaded model
+            let ctx = if jobs || ingest {
+                whisper_rs::install_whisper_log_trampoline();
+                Some(Arc::new(load_whisper_context(model_download_url)?))
+            } else {
+                None
+            };
+            // and one speaker embedding model
+            let speakers = match (&ctx, &config.diarization) {
+                (Some(_), Some(diarization)) => Some(Arc::new(SpeakerEmbedder::new(diarization)?)),
+                _ => None,
+            };
+            let job_manager = match (&ctx, jobs) {
+                (Some(ctx), true) => Some(JobManager::new(ctx.clone(), n_threads, config.decoding.clone(), config.postprocess.clone(), speakers.clone())),
+                _ => None,
+            };
+            let ingest_manager = match (&ctx, ingest) {
+                (Some(ctx), true) => Some(IngestManager::new(ctx.clone(), get_filename_from_url(model_download_url)?, n_threads, &config, speakers.clone())?),
+                _ => None,
+            };
+            serve_transcripts(&config, SocketAddr::new(host, port), job_manager, ingest_manager)?;
+        },
+        Commands::Search { query, show_name, from, to, limit } => {
+            let params = SearchParams {
+                query,
+                show_name,
+                from: from.as_deref().map(parse_timestamp).transpose()?,
+                to: to.as_deref().map(parse_timestamp).transpose()?,
+                limit,
+            };
+            for result in search_from_config(&config, &params)? {
+                println!("{}", serde_json::to_string(&result)?);
+            }
+        },
+        Commands::ExportCorrections { show_name, from, to } => {
+            let from = from.as_deref().map(parse_timestamp).transpose()?;
+            let to = to.as_deref().map(parse_timestamp).transpose()?;
+            for correction in export_corrections_from_config(&config, show_name.as_deref(), from, to)? {
+                println!("{}", serde_json::to_string(&correction)?);
+            }
+        },
+        Commands::Enqueue { source, show_name, model, language, priority, max_attempts, recorded_at } => {
+            let job = NewJob {
+                source,
+                show_name: show_name.unwrap_or_else(|| config.show_name.clone()),
+                model: model.unwrap_or_else(|| "turbo".to_string()),
+                language: language.unwrap_or_else(|| config.language.clone()),
+                priority,
+                max_attempts,
+                recorded_at: recorded_at.as_deref().map(parse_timestamp).transpose()?.map(|ts| ts.naive_utc()),
+            };
+            let id = enqueue_job(&config, &job)?;
+            println!("{}", id);
+        },
+        Commands::Work { worker_id, num_transcribe_threads, poll_seconds, lease_seconds } => {
+            init_logging("queue_worker")?;
+            whisper_rs::install_whisper_log_trampoline();
+            let options = WorkerOptions {
+                worker_id: worker_id.unwrap_or_else(|| format!("worker-{}", process::id())),
+                n_threads: num_transcribe_threads.unwrap_or_else(default_num_transcribe_threads),
+                poll_interval: Duration::from_secs(poll_seconds),
+                lease_seconds,
+            };
+            run_queue_worker(&config, &options)?;
+        },
+        Commands::Wyoming { num_transcribe_threads } => {
+            init_logging("wyoming")?;
+            whisper_rs::install_whisper_log_trampoline();
+            serve_wyoming(&config, num_transcribe_threads.unwrap_or_else(default_num_transcribe_threads))?;
+        },
     };
     
     Ok(())
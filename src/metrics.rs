@@ -0,0 +1,219 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use chrono::Utc;
+use prometheus::{Counter, CounterVec, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use serde::Serialize;
+
+// a show that has not received audio for this long is reported unhealthy
+pub const STALE_AUDIO_SECONDS: i64 = 120;
+
+// number of vad chunks (1024 samples each) the speech ratio is computed over, about a minute
+const SPEECH_RATIO_WINDOW: usize = 940;
+
+struct Metrics {
+    registry: Registry,
+    channel_depth: IntGaugeVec,
+    audio_seconds: CounterVec,
+    speech_seconds: CounterVec,
+    speech_ratio: GaugeVec,
+    last_audio_timestamp: IntGaugeVec,
+    segments: IntCounterVec,
+    real_time_factor: HistogramVec,
+    db_insert_seconds: HistogramVec,
+    db_insert_failures: IntCounterVec,
+    ffmpeg_restarts: IntCounterVec,
+    filtered_segments: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new();
+
+    let channel_depth = IntGaugeVec::new(
+        Opts::new("transcribe_channel_depth", "audio chunks waiting in the queue in front of vad"), &["show"]).unwrap();
+    let audio_seconds = CounterVec::new(
+        Opts::new("transcribe_vad_audio_seconds_total", "seconds of audio run through vad"), &["show"]).unwrap();
+    let speech_seconds = CounterVec::new(
+        Opts::new("transcribe_vad_speech_seconds_total", "seconds of audio vad classified as speech"), &["show"]).unwrap();
+    let speech_ratio = GaugeVec::new(
+        Opts::new("transcribe_vad_speech_ratio", "fraction of the last minute of audio classified as speech"), &["show"]).unwrap();
+    let last_audio_timestamp = IntGaugeVec::new(
+        Opts::new("transcribe_last_audio_timestamp_seconds", "unix time the last audio chunk was received"), &["show"]).unwrap();
+    let segments = IntCounterVec::new(
+        Opts::new("transcribe_segments_total", "transcript segments produced by whisper"), &["show"]).unwrap();
+    let real_time_factor = HistogramVec::new(
+        HistogramOpts::new("transcribe_whisper_real_time_factor", "whisper processing time divided by audio duration")
+            .buckets(vec![0.05, 0.1, 0.2, 0.3, 0.5, 0.75, 1.0, 1.5, 2.0, 5.0]), &["show"]).unwrap();
+    let db_insert_seconds = HistogramVec::new(
+        HistogramOpts::new("transcribe_db_insert_seconds", "latency of saving a transcript to the database")
+            .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0]), &["show"]).unwrap();
+    let db_insert_failures = IntCounterVec::new(
+        Opts::new("transcribe_db_insert_failures_total", "transcripts that failed to save to the database"), &["show"]).unwrap();
+    let ffmpeg_restarts = IntCounterVec::new(
+        Opts::new("transcribe_ffmpeg_restarts_total", "times ffmpeg was restarted after a live stream stopped"), &["show"]).unwrap();
+
+    registry.register(Box::new(channel_depth.clone())).unwrap();
+    registry.register(Box::new(audio_seconds.clone())).unwrap();
+    registry.register(Box::new(speech_seconds.clone())).unwrap();
+    registry.register(Box::new(speech_ratio.clone())).unwrap();
+    registry.register(Box::new(last_audio_timestamp.clone())).unwrap();
+    registry.register(Box::new(segments.clone())).unwrap();
+    registry.register(Box::new(real_time_factor.clone())).unwrap();
+    registry.register(Box::new(db_insert_seconds.clone())).unwrap();
+    registry.register(Box::new(db_insert_failures.clone())).unwrap();
+    let filtered_segments = IntCounterVec::new(
+        Opts::new("transcribe_filtered_segments_total", "segments caught by the hallucination filter"), &["show", "reason"]).unwrap();
+    registry.register(Box::new(ffmpeg_restarts.clone())).unwrap();
+    registry.register(Box::new(filtered_segments.clone())).unwrap();
+
+    Metrics {
+        registry,
+        channel_depth,
+        audio_seconds,
+        speech_seconds,
+        speech_ratio,
+        last_audio_timestamp,
+        segments,
+        real_time_factor,
+        db_insert_seconds,
+        db_insert_failures,
+        ffmpeg_restarts,
+        filtered_segments,
+    }
+});
+
+// shows being transcribed by this process, for /healthz
+static SHOWS: LazyLock<Mutex<HashMap<String, Arc<ShowMetrics>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Metric handles with the show label already applied.
+pub struct ShowMetrics {
+    show_name: String,
+    channel_depth: IntGauge,
+    audio_seconds: Counter,
+    speech_seconds: Counter,
+    speech_ratio: Gauge,
+    last_audio_timestamp: IntGauge,
+    segments: IntCounter,
+    real_time_factor: Histogram,
+    db_insert_seconds: Histogram,
+    db_insert_failures: IntCounter,
+    ffmpeg_restarts: IntCounter,
+    recent_speech: Mutex<VecDeque<bool>>,
+    // audio is expected to arrive continuously, e.g. a url or microphone rather than the web page
+    live: AtomicBool,
+}
+
+impl ShowMetrics {
+    /// Get the metrics of a show and set whether it is live, paused or stopped shows are not.
+    pub fn for_show(show_name: &str, live: bool) -> Arc<ShowMetrics> {
+        let mut shows = SHOWS.lock().unwrap();
+        let metrics = shows.entry(show_name.to_string()).or_insert_with(|| {
+            let m = &*METRICS;
+            let labels = &[show_name];
+            Arc::new(ShowMetrics {
+                show_name: show_name.to_string(),
+                channel_depth: m.channel_depth.with_label_values(labels),
+                audio_seconds: m.audio_seconds.with_label_values(labels),
+                speech_seconds: m.speech_seconds.with_label_values(labels),
+                speech_ratio: m.speech_ratio.with_label_values(labels),
+                last_audio_timestamp: m.last_audio_timestamp.with_label_values(labels),
+                segments: m.segments.with_label_values(labels),
+                real_time_factor: m.real_time_factor.with_label_values(labels),
+                db_insert_seconds: m.db_insert_seconds.with_label_values(labels),
+                db_insert_failures: m.db_insert_failures.with_label_values(labels),
+                ffmpeg_restarts: m.ffmpeg_restarts.with_label_values(labels),
+                recent_speech: Mutex::new(VecDeque::with_capacity(SPEECH_RATIO_WINDOW)),
+                live: AtomicBool::new(live),
+            })
+        }).clone();
+        metrics.live.store(live, Ordering::Relaxed);
+        metrics
+    }
+
+    pub fn observe_vad_chunk(&self, channel_depth: usize, chunk_seconds: f64, has_speech: bool) {
+        self.channel_depth.set(channel_depth as i64);
+        self.audio_seconds.inc_by(chunk_seconds);
+        if has_speech {
+            self.speech_seconds.inc_by(chunk_seconds);
+        }
+        self.last_audio_timestamp.set(Utc::now().timestamp());
+
+        let mut recent = self.recent_speech.lock().unwrap();
+        if recent.len() == SPEECH_RATIO_WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(has_speech);
+        let speech = recent.iter().filter(|has_speech| **has_speech).count();
+        self.speech_ratio.set(speech as f64 / recent.len() as f64);
+    }
+
+    pub fn observe_transcription(&self, processing_seconds: f64, audio_seconds: f64, segments: usize) {
+        if audio_seconds > 0.0 {
+            self.real_time_factor.observe(processing_seconds / audio_seconds);
+        }
+        self.segments.inc_by(segments as u64);
+    }
+
+    pub fn observe_db_insert(&self, seconds: f64, success: bool) {
+        self.db_insert_seconds.observe(seconds);
+        if !success {
+            self.db_insert_failures.inc();
+        }
+    }
+
+    pub fn inc_ffmpeg_restarts(&self) {
+        self.ffmpeg_restarts.inc();
+    }
+
+    pub fn inc_filtered(&self, reason: &str) {
+        METRICS.filtered_segments.with_label_values(&[self.show_name.as_str(), reason]).inc();
+    }
+
+    // seconds since the last audio chunk, None if no audio has been received yet
+    fn audio_age_seconds(&self) -> Option<i64> {
+        match self.last_audio_timestamp.get() {
+            0 => None,
+            ts => Some(Utc::now().timestamp() - ts),
+        }
+    }
+}
+
+/// All metrics in the prometheus text format.
+pub fn gather_metrics() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[derive(Serialize)]
+pub struct ShowHealth {
+    pub show_name: String,
+    pub last_audio_age_seconds: Option<i64>,
+    pub healthy: bool,
+}
+
+#[derive(Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub shows: Vec<ShowHealth>,
+}
+
+/// A live show is unhealthy when it has stopped receiving audio, shows still waiting
+/// for their first chunk and shows fed from the web page are reported but don't fail the check.
+pub fn check_health() -> Health {
+    let shows = SHOWS.lock().unwrap();
+    let mut result: Vec<ShowHealth> = shows.iter().map(|(show_name, metrics)| {
+        let last_audio_age_seconds = metrics.audio_age_seconds();
+        ShowHealth {
+            show_name: show_name.clone(),
+            last_audio_age_seconds,
+            healthy: !metrics.live.load(Ordering::Relaxed) || last_audio_age_seconds.map(|age| age <= STALE_AUDIO_SECONDS).unwrap_or(true),
+        }
+    }).collect();
+    result.sort_by(|a, b| a.show_name.cmp(&b.show_name));
+    Health {
+        healthy: result.iter().all(|show| show.healthy),
+        shows: result,
+    }
+}
@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use zhconv::{zhconv, Variant};
+
+use crate::vad_processor::TranscriptSegment;
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ChineseVariant {
+    #[serde(rename = "zh-Hant")]
+    ZhHant,
+    #[serde(rename = "zh-HK")]
+    ZhHK,
+    #[serde(rename = "zh-TW")]
+    ZhTW,
+    #[serde(rename = "zh-Hans")]
+    ZhHans,
+    // whisper output as is
+    #[serde(rename = "none")]
+    Unchanged,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Replacement {
+    pub find: String,
+    pub replace: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberFormat {
+    // full width digits to ascii
+    Halfwidth,
+    // also 12345 to 12,345, shorter numbers like years are left alone
+    Grouped,
+}
+
+/// `[postprocess]` section of the config, what is done to whisper's text before it is saved
+/// or returned, in order: chinese conversion, replacements, spacing, numbers.
+///
+/// Without the section zh and yue are converted to zh-Hant and nothing else changes.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PostProcessConfig {
+    // only applies to zh and yue
+    #[serde(default = "default_chinese_variant")]
+    pub chinese_variant: ChineseVariant,
+    // plain text, after the chinese conversion so write finds in the converted variant
+    #[serde(default)]
+    pub replace: Vec<Replacement>,
+    // no spaces within cjk, one space between cjk and latin text, full width punctuation after cjk
+    #[serde(default)]
+    pub normalize_spacing: bool,
+    pub number_format: Option<NumberFormat>,
+}
+
+fn default_chinese_variant() -> ChineseVariant {
+    ChineseVariant::ZhHant
+}
+
+impl Default for PostProcessConfig {
+    fn default() -> Self {
+        Self {
+            chinese_variant: default_chinese_variant(),
+            replace: Vec::new(),
+            normalize_spacing: false,
+            number_format: None,
+        }
+    }
+}
+
+// han and kana, written without spaces between words, unlike hangul
+pub(crate) fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F)
+}
+
+fn is_fullwidth_punctuation(c: char) -> bool {
+    matches!(c, '，' | '。' | '？' | '！' | '：' | '；' | '、' | '「' | '」' | '『' | '』' | '（' | '）' | '《' | '》' | '“' | '”')
+}
+
+fn to_fullwidth_punctuation(c: char) -> Option<char> {
+    match c {
+        ',' => Some('，'),
+        '.' => Some('。'),
+        '?' => Some('？'),
+        '!' => Some('！'),
+        ':' => Some('：'),
+        ';' => Some('；'),
+        _ => None,
+    }
+}
+
+// the latin word at the start of `chars` has a letter in it
+fn has_letter<'a>(chars: impl Iterator<Item = &'a char>) -> bool {
+    chars.take_while(|c| c.is_ascii_alphanumeric()).any(|c| c.is_ascii_alphabetic())
+}
+
+fn normalize_spacing(text: &str) -> String {
+    // collapse whitespace first so only single spaces are left to look at
+    let chars: Vec<char> = text.split_whitespace().collect::<Vec<_>>().join(" ").chars().collect();
+    let mut out: Vec<char> = Vec::with_capacity(chars.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let prev = out.last().copied();
+        if c == ' ' {
+            let next = chars.get(i + 1).copied();
+            let joins_cjk = |c: Option<char>| c.map(|c| is_cjk(c) || is_fullwidth_punctuation(c)).unwrap_or(false);
+            if joins_cjk(prev) && joins_cjk(next) {
+                continue;
+            }
+            out.push(c);
+            continue;
+        }
+        let c = match (prev, to_fullwidth_punctuation(c)) {
+            (Some(prev), Some(fullwidth)) if is_cjk(prev) => fullwidth,
+            _ => c,
+        };
+        // numbers stay next to their measure word, as in 2024年 or 3個
+        if let Some(prev) = prev {
+            if (is_cjk(prev) && has_letter(chars[i..].iter()))
+                || (prev.is_ascii_alphanumeric() && is_cjk(c) && has_letter(out.iter().rev())) {
+                out.push(' ');
+            }
+        }
+        out.push(c);
+    }
+    out.into_iter().collect()
+}
+
+fn group_digits(digits: &str) -> String {
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    // digits before the first comma
+    let lead = digits.len() % 3;
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && i % 3 == lead {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+fn format_numbers(text: &str, number_format: NumberFormat) -> String {
+    let halfwidth: String = text.chars().map(|c| match c {
+        '０'..='９' => char::from_u32(c as u32 - '０' as u32 + '0' as u32).unwrap_or(c),
+        _ => c,
+    }).collect();
+    if number_format == NumberFormat::Halfwidth {
+        return halfwidth;
+    }
+
+    let chars: Vec<char> = halfwidth.chars().collect();
+    let mut out = String::with_capacity(halfwidth.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if !chars[i].is_ascii_digit() {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && chars[i].is_ascii_digit() {
+            i += 1;
+        }
+        let digits: String = chars[start..i].iter().collect();
+        // leave decimals and numbers that are already grouped alone
+        let touches_separator = |c: Option<&char>| matches!(c, Some('.') | Some(','));
+        let part_of_larger = (start > 0 && touches_separator(chars.get(start - 1))) || touches_separator(chars.get(i));
+        // eight digits is a hong kong phone number
+        if digits.len() >= 5 && digits.len() != 8 && !part_of_larger {
+            out.push_str(&group_digits(&digits));
+        } else {
+            out.push_str(&digits);
+        }
+    }
+    out
+}
+
+impl PostProcessConfig {
+    /// Only the chinese conversion, which keeps characters one to one, e.g. for word timestamps.
+    pub fn convert_chinese(&self, language: &str, text: String) -> String {
+        if !matches!(language, "zh" | "yue") {
+            return text;
+        }
+        let variant = match self.chinese_variant {
+            ChineseVariant::ZhHant => Variant::ZhHant,
+            ChineseVariant::ZhHK => Variant::ZhHK,
+            ChineseVariant::ZhTW => Variant::ZhTW,
+            ChineseVariant::ZhHans => Variant::ZhHans,
+            ChineseVariant::Unchanged => return text,
+        };
+        zhconv(&text, variant)
+    }
+
+    /// The whole chain, whisper's text in `language` as it is saved and returned.
+    pub fn process(&self, language: &str, text: &str) -> String {
+        let mut text = self.convert_chinese(language, text.to_string());
+        for replacement in &self.replace {
+            if !replacement.find.is_empty() {
+                text = text.replace(&replacement.find, &replacement.replace);
+            }
+        }
+        if self.normalize_spacing {
+            text = normalize_spacing(&text);
+        }
+        if let Some(number_format) = self.number_format {
+            text = format_numbers(&text, number_format);
+        }
+        text
+    }
+
+    /// Process the text and words of a segment in place, keeping whisper's text in raw_text.
+    pub fn process_segment(&self, language: &str, segment: &mut TranscriptSegment) {
+        let text = self.process(language, &segment.text);
+        for word in segment.words.iter_mut() {
+            word.word = self.convert_chinese(language, std::mem::take(&mut word.word));
+        }
+        segment.raw_text = Some(std::mem::replace(&mut segment.text, text));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_digits_in_threes() {
+        assert_eq!(group_digits(""), "");
+        assert_eq!(group_digits("7"), "7");
+        assert_eq!(group_digits("123"), "123");
+        assert_eq!(group_digits("1234"), "1,234");
+        assert_eq!(group_digits("12345"), "12,345");
+        assert_eq!(group_digits("123456"), "123,456");
+        assert_eq!(group_digits("1234567"), "1,234,567");
+    }
+
+    #[test]
+    fn normalize_spacing_of_empty_and_latin_text() {
+        assert_eq!(normalize_spacing(""), "");
+        assert_eq!(normalize_spacing("   "), "");
+        assert_eq!(normalize_spacing(" hello   world, ok? "), "hello world, ok?");
+    }
+
+    #[test]
+    fn no_spaces_within_cjk() {
+        assert_eq!(normalize_spacing("你 好 嗎"), "你好嗎");
+        assert_eq!(normalize_spacing("好 。 走"), "好。走");
+    }
+
+    #[test]
+    fn one_space_between_cjk_and_latin() {
+        assert_eq!(normalize_spacing("我用iPhone打電話"), "我用 iPhone 打電話");
+        assert_eq!(normalize_spacing("我用  iPhone  打電話"), "我用 iPhone 打電話");
+    }
+
+    #[test]
+    fn no_space_between_numbers_and_cjk() {
+        assert_eq!(normalize_spacing("2024年"), "2024年");
+        assert_eq!(normalize_spacing("第3名有500個人"), "第3名有500個人");
+        assert_eq!(normalize_spacing("我用iPhone15打電話"), "我用 iPhone15 打電話");
+        assert_eq!(normalize_spacing("打3D打印"), "打 3D 打印");
+    }
+
+    #[test]
+    fn group_numbers_except_phone_numbers() {
+        assert_eq!(format_numbers("共12345人", NumberFormat::Grouped), "共12,345人");
+        assert_eq!(format_numbers("$1234567", NumberFormat::Grouped), "$1,234,567");
+        assert_eq!(format_numbers("電話23456789", NumberFormat::Grouped), "電話23456789");
+        assert_eq!(format_numbers("123456789", NumberFormat::Grouped), "123,456,789");
+        assert_eq!(format_numbers("3.14159 1,234", NumberFormat::Grouped), "3.14159 1,234");
+    }
+
+    #[test]
+    fn fullwidth_punctuation_after_cjk() {
+        assert_eq!(normalize_spacing("好,走吧!"), "好，走吧！");
+        assert_eq!(normalize_spacing("ok, 好"), "ok, 好");
+    }
+}
@@ -0,0 +1,218 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use whisper_rs::{FullParams, WhisperContext, WhisperToken};
+
+use crate::db::{LOW_CONFIDENCE_AVG_LOGPROB, LOW_CONFIDENCE_NO_SPEECH_PROB};
+use crate::filter::has_repetition;
+use crate::vad_processor::TranscriptSegment;
+
+/// `[prompt]` section of the config, biases whisper towards the names and terms of a show.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct PromptConfig {
+    // free text, e.g. a sentence in the style of the show
+    pub text: Option<String>,
+    #[serde(default)]
+    pub vocabulary: Vec<String>,
+    // more terms, one per line, read again when it changes without restarting
+    pub vocabulary_file: Option<String>,
+    // [prompt.context], carry the end of the previous speech clip over to the next one
+    pub context: Option<ContextConfig>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ContextConfig {
+    // characters of the previous text to keep
+    #[serde(default = "default_max_chars")]
+    pub max_chars: usize,
+    // a gap between speech clips longer than this starts over
+    #[serde(default = "default_reset_after_silence_seconds")]
+    pub reset_after_silence_seconds: f32,
+}
+
+fn default_max_chars() -> usize {
+    100
+}
+
+fn default_reset_after_silence_seconds() -> f32 {
+    10.0
+}
+
+/// Initial prompt of a show: the free text prompt followed by the key terms from the config
+/// and from the vocabulary file, which is read again whenever it changes.
+///
+/// whisper only keeps the last 64 tokens of the prompt (n_max_text_ctx), so keep it short.
+pub struct ShowPrompt {
+    prompt: Option<String>,
+    vocabulary: Vec<String>,
+    // one term per line, blank lines and lines starting with # are skipped
+    vocabulary_file: Option<PathBuf>,
+    file_modified: Option<SystemTime>,
+    file_terms: Vec<String>,
+    // what params were last set to
+    applied: Option<String>,
+    context_config: Option<ContextConfig>,
+    // whisper output of the last speech clips, up to max_chars
+    context: String,
+    // end of the last speech clip, to tell the silence since
+    context_end_ms: Option<i64>,
+}
+
+fn read_terms(path: &PathBuf) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+impl ShowPrompt {
+    pub fn new(config: &PromptConfig) -> Self {
+        Self {
+            prompt: config.text.as_deref().map(str::trim).filter(|text| !text.is_empty()).map(str::to_string),
+            vocabulary: config.vocabulary.iter()
+                .map(|term| term.trim().to_string())
+                .filter(|term| !term.is_empty())
+                .collect(),
+            vocabulary_file: config.vocabulary_file.as_ref().map(PathBuf::from),
+            file_modified: None,
+            file_terms: Vec::new(),
+            applied: None,
+            context_config: config.context.clone(),
+            context: String::new(),
+            context_end_ms: None,
+        }
+    }
+
+    // keeps the last terms read when the file is missing or unreadable
+    fn reload_file(&mut self) {
+        let path = match &self.vocabulary_file {
+            Some(path) => path,
+            None => return,
+        };
+        let modified = match std::fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!("failed to read vocabulary file {}: {}", path.display(), e);
+                return;
+            }
+        };
+        if self.file_modified == Some(modified) {
+            return;
+        }
+        match read_terms(path) {
+            Ok(terms) => {
+                info!("loaded {} terms from vocabulary file {}", terms.len(), path.display());
+                self.file_terms = terms;
+                self.file_modified = Some(modified);
+            },
+            Err(e) => warn!("failed to read vocabulary file {}: {}", path.display(), e),
+        }
+    }
+
+    /// The prompt whisper is given, None when there is nothing to prompt with.
+    pub fn text(&self) -> Option<String> {
+        let terms: Vec<&str> = self.vocabulary.iter().chain(self.file_terms.iter())
+            .map(|term| term.as_str())
+            .collect();
+        let mut text = self.prompt.clone().unwrap_or_default();
+        if !terms.is_empty() {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(&terms.join(", "));
+        }
+        if text.is_empty() {
+            None
+        } else {
+            Some(text)
+        }
+    }
+
+    /// The prompt with the vocabulary file as it is now.
+    pub fn current(&mut self) -> Option<String> {
+        self.reload_file();
+        self.text()
+    }
+
+    /// Set the initial prompt on `params` if it changed since the last call.
+    pub fn apply(&mut self, params: &mut FullParams) {
+        let text = self.current();
+        if text == self.applied {
+            return;
+        }
+        // whisper-rs hands the prompt over to whisper.cpp for good, so only set it when it changes
+        params.set_initial_prompt(text.as_deref().unwrap_or(""));
+        self.applied = text;
+    }
+
+    /// Tokens of the prompt followed by the carried over context, for the speech clip starting
+    /// at `timestamp_millis`. None without context, then the initial prompt set by apply is used.
+    pub fn context_tokens(&mut self, ctx: &WhisperContext, timestamp_millis: Option<i64>) -> Option<Vec<WhisperToken>> {
+        let context_config = self.context_config.as_ref()?;
+        if let (Some(start), Some(end)) = (timestamp_millis, self.context_end_ms) {
+            if (start - end) as f32 > context_config.reset_after_silence_seconds * 1000.0 {
+                debug!("resetting context after {} ms of silence", start - end);
+                self.reset_context();
+            }
+        }
+        if self.context.is_empty() {
+            return None;
+        }
+        let text = match self.text() {
+            Some(prompt) => format!("{} {}", prompt, self.context),
+            None => self.context.clone(),
+        };
+        // whisper tokenizes text after a space
+        match ctx.tokenize(&format!(" {}", text), 1024) {
+            Ok(tokens) => Some(tokens),
+            Err(e) => {
+                warn!("failed to tokenize context: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Keep the end of what whisper made of the speech clip for the next one, unless it looks
+    /// like a hallucination, which would only be repeated.
+    pub fn remember(&mut self, timestamp_millis: Option<i64>, duration_ms: i64, segments: &[TranscriptSegment]) {
+        let max_chars = match &self.context_config {
+            Some(context_config) => context_config.max_chars,
+            None => return,
+        };
+        self.context_end_ms = timestamp_millis.map(|timestamp_millis| timestamp_millis + duration_ms);
+
+        let unsure = segments.iter().any(|segment| {
+            segment.confidence.avg_logprob.is_some_and(|p| p < LOW_CONFIDENCE_AVG_LOGPROB)
+                || segment.confidence.no_speech_prob.is_some_and(|p| p > LOW_CONFIDENCE_NO_SPEECH_PROB)
+                || has_repetition(&segment.text)
+        });
+        if unsure {
+            debug!("resetting context after an unsure speech clip");
+            self.reset_context();
+            return;
+        }
+
+        for segment in segments {
+            let text = segment.text.trim();
+            if text.is_empty() {
+                continue;
+            }
+            if !self.context.is_empty() {
+                self.context.push(' ');
+            }
+            self.context.push_str(text);
+        }
+        let chars = self.context.chars().count();
+        if chars > max_chars {
+            self.context = self.context.chars().skip(chars - max_chars).collect();
+        }
+    }
+
+    pub fn reset_context(&mut self) {
+        self.context.clear();
+    }
+}
@@ -0,0 +1,237 @@
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+use sqlx::{Pool, Postgres, Row};
+
+use crate::config::Config;
+use crate::db::init_db_without_backfill;
+use crate::runtime_utils::get_runtime;
+
+const SNIPPET_CHARS: usize = 80;
+pub const MAX_SEARCH_LIMIT: i64 = 500;
+
+pub struct SearchParams {
+    pub query: String,
+    pub show_name: Option<String>,
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: Option<DateTime<FixedOffset>>,
+    pub limit: i64,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    pub id: i64,
+    pub show_name: String,
+    pub timestamp: String,
+    pub content: String,
+    pub snippet: String,
+    pub rank: f32,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF // hiragana, katakana
+        | 0x3400..=0x4DBF // cjk extension a
+        | 0x4E00..=0x9FFF // cjk unified ideographs
+        | 0xAC00..=0xD7AF // hangul syllables
+        | 0xF900..=0xFAFF // cjk compatibility ideographs
+        | 0x20000..=0x2FFFF // cjk extension b and later
+    )
+}
+
+/// Split text into groups of search tokens.
+///
+/// Latin words become a single lowercased token. A run of cjk characters has no
+/// word boundaries, so it becomes overlapping bigrams which are matched as a phrase.
+fn token_groups(text: &str) -> Vec<Vec<String>> {
+    let mut groups = Vec::new();
+    let mut word = String::new();
+    let mut run: Vec<char> = Vec::new();
+
+    let flush_word = |word: &mut String, groups: &mut Vec<Vec<String>>| {
+        if !word.is_empty() {
+            groups.push(vec![std::mem::take(word)]);
+        }
+    };
+    let flush_run = |run: &mut Vec<char>, groups: &mut Vec<Vec<String>>| {
+        match run.len() {
+            0 => {},
+            1 => groups.push(vec![run[0].to_string()]),
+            _ => groups.push(run.windows(2).map(|w| w.iter().collect()).collect()),
+        }
+        run.clear();
+    };
+
+    for c in text.chars() {
+        if is_cjk(c) {
+            flush_word(&mut word, &mut groups);
+            run.push(c);
+        } else if c.is_alphanumeric() {
+            flush_run(&mut run, &mut groups);
+            word.extend(c.to_lowercase());
+        } else {
+            flush_word(&mut word, &mut groups);
+            flush_run(&mut run, &mut groups);
+        }
+    }
+    flush_word(&mut word, &mut groups);
+    flush_run(&mut run, &mut groups);
+    groups
+}
+
+/// Tokens stored in `transcripts.search_text` and indexed as a `tsvector`.
+///
+/// The last character of each cjk run is also emitted on its own so that a
+/// single character query can always be found with a prefix match.
+pub fn search_text(content: &str) -> String {
+    let mut tokens = Vec::new();
+    for group in token_groups(content) {
+        let is_cjk_run = group[0].chars().next().map(is_cjk).unwrap_or(false);
+        let last_char = group.last().and_then(|t| t.chars().last());
+        let multiple = group.len() > 1 || group[0].chars().count() > 1;
+        tokens.extend(group);
+        if is_cjk_run && multiple {
+            tokens.push(last_char.unwrap().to_string());
+        }
+    }
+    tokens.join(" ")
+}
+
+/// Build a `to_tsquery('simple', ..)` expression matching all the words in the query.
+pub fn search_query(query: &str) -> Option<String> {
+    let parts: Vec<String> = token_groups(query).into_iter().map(|group| {
+        if group.len() == 1 && group[0].chars().count() == 1 && is_cjk(group[0].chars().next().unwrap()) {
+            format!("{}:*", group[0])
+        } else {
+            group.join(" <-> ")
+        }
+    }).collect();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" & "))
+    }
+}
+
+/// Cut a window of the content around the first match and wrap matches in `<b></b>`.
+pub fn highlight_snippet(content: &str, query: &str) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let lower: Vec<char> = chars.iter().map(|c| c.to_lowercase().next().unwrap_or(*c)).collect();
+    let terms: Vec<Vec<char>> = query.split_whitespace()
+        .map(|t| t.chars().map(|c| c.to_lowercase().next().unwrap_or(c)).collect())
+        .collect();
+
+    let mut matched = vec![false; chars.len()];
+    for term in terms.iter().filter(|t| !t.is_empty()) {
+        let mut i = 0;
+        while i + term.len() <= lower.len() {
+            if lower[i..i + term.len()] == term[..] {
+                matched[i..i + term.len()].iter_mut().for_each(|m| *m = true);
+                i += term.len();
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    let first = matched.iter().position(|m| *m).unwrap_or(0);
+    let start = first.saturating_sub(SNIPPET_CHARS / 3);
+    let end = (start + SNIPPET_CHARS).min(chars.len());
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    for i in start..end {
+        if matched[i] && (i == start || !matched[i - 1]) {
+            snippet.push_str("<b>");
+        }
+        snippet.push(chars[i]);
+        if matched[i] && (i + 1 == end || !matched[i + 1]) {
+            snippet.push_str("</b>");
+        }
+    }
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+pub fn parse_timestamp(ts: &str) -> Result<DateTime<FixedOffset>, Box<dyn std::error::Error>> {
+    Ok(DateTime::parse_from_rfc3339(ts)?)
+}
+
+pub async fn search_transcripts(pool: &Pool<Postgres>, params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let tsquery = match search_query(&params.query) {
+        Some(tsquery) => tsquery,
+        None => return Ok(Vec::new()),
+    };
+
+    let rows = sqlx::query(r#"SELECT id, show_name, "timestamp", coalesce(corrected_content, content) AS content, ts_rank(search_vector, query) AS rank
+        FROM transcripts, to_tsquery('simple', $1) query
+        WHERE search_vector @@ query
+        AND ($2::varchar IS NULL OR show_name = $2)
+        AND ($3::timestamp IS NULL OR "timestamp" >= $3)
+        AND ($4::timestamp IS NULL OR "timestamp" < $4)
+        ORDER BY "timestamp" DESC
+        LIMIT $5"#)
+    .bind(tsquery)
+    .bind(params.show_name.as_deref())
+    .bind(params.from.map(|ts| ts.naive_utc()))
+    .bind(params.to.map(|ts| ts.naive_utc()))
+    .bind(params.limit.clamp(1, MAX_SEARCH_LIMIT))
+    .fetch_all(pool).await?;
+
+    let mut results = Vec::with_capacity(rows.len());
+    for row in rows {
+        let timestamp: chrono::NaiveDateTime = row.try_get("timestamp")?;
+        let content: String = row.try_get("content")?;
+        results.push(SearchResult {
+            id: row.try_get("id")?,
+            show_name: row.try_get("show_name")?,
+            timestamp: timestamp.and_utc().to_rfc3339(),
+            snippet: highlight_snippet(&content, &params.query),
+            content,
+            rank: row.try_get("rank")?,
+        });
+    }
+    Ok(results)
+}
+
+pub fn search_from_config(config: &Config, params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error>> {
+    let rt = get_runtime();
+    let database_config = config.database_config.as_ref().ok_or("database_config is required for search")?;
+    let pool = init_db_without_backfill(rt, database_config)?;
+    rt.block_on(search_transcripts(&pool, params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_text_has_no_tokens() {
+        assert!(token_groups("").is_empty());
+        assert_eq!(search_text(""), "");
+        assert_eq!(search_query(""), None);
+        assert_eq!(search_query(" ,. "), None);
+    }
+
+    #[test]
+    fn cjk_run_becomes_bigrams() {
+        assert_eq!(token_groups("天氣好"), vec![vec!["天氣", "氣好"]]);
+        assert_eq!(search_text("天氣好"), "天氣 氣好 好");
+        assert_eq!(search_query("天氣好"), Some("天氣 <-> 氣好".to_string()));
+    }
+
+    #[test]
+    fn single_cjk_character_is_a_prefix_match() {
+        assert_eq!(search_text("好"), "好");
+        assert_eq!(search_query("港"), Some("港:*".to_string()));
+    }
+
+    #[test]
+    fn mixed_cjk_and_latin() {
+        assert_eq!(token_groups("Hello香港World 2024"), vec![vec!["hello"], vec!["香港"], vec!["world"], vec!["2024"]]);
+        assert_eq!(search_query("香港 News"), Some("香港 & news".to_string()));
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::postgres::PgExecutor;
+use sqlx::{Pool, Postgres, Row};
+
+// a running ingest updates last_heartbeat_at this often
+pub const SHOW_HEARTBEAT_SECONDS: u64 = 30;
+
+// missing this many heartbeats in a row means the ingest is gone
+const MISSED_HEARTBEATS: u64 = 3;
+
+#[derive(Serialize)]
+pub struct ShowInfo {
+    pub show_name: String,
+    pub row_count: i64,
+    pub first_timestamp: Option<String>,
+    pub last_timestamp: Option<String>,
+    pub language: Option<String>,
+    pub model: Option<String>,
+    pub speech_hours: f64,
+    pub last_heartbeat_at: Option<String>,
+    pub is_live: bool,
+}
+
+/// Record the language and model a show is transcribed with.
+pub async fn register_show<'c, E>(executor: E, show_name: &str, language: &str, model: &str) -> Result<(), Box<dyn std::error::Error>>
+where
+    E: PgExecutor<'c>,
+{
+    sqlx::query(r#"INSERT INTO shows (show_name, language, model) VALUES ($1, $2, $3)
+        ON CONFLICT (show_name) DO UPDATE SET language = excluded.language, model = excluded.model"#)
+        .bind(show_name)
+        .bind(language)
+        .bind(model)
+        .execute(executor).await?;
+    Ok(())
+}
+
+/// Mark the ingest of a show as running.
+pub async fn show_heartbeat(pool: &Pool<Postgres>, show_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    sqlx::query(r#"UPDATE shows SET last_heartbeat_at = (now() at time zone 'utc') WHERE show_name = $1"#)
+        .bind(show_name)
+        .execute(pool).await?;
+    Ok(())
+}
+
+pub async fn list_shows(pool: &Pool<Postgres>) -> Result<Vec<ShowInfo>, Box<dyn std::error::Error>> {
+    let rows = sqlx::query(r#"SELECT show_name, row_count, first_timestamp, last_timestamp, language, model, speech_seconds, last_heartbeat_at,
+            coalesce(last_heartbeat_at > (now() at time zone 'utc') - make_interval(secs => $1), false) AS is_live
+        FROM shows ORDER BY show_name"#)
+        .bind((SHOW_HEARTBEAT_SECONDS * MISSED_HEARTBEATS) as f64)
+        .fetch_all(pool).await?;
+
+    let to_rfc3339 = |ts: Option<NaiveDateTime>| ts.map(|ts| ts.and_utc().to_rfc3339());
+
+    let mut shows = Vec::with_capacity(rows.len());
+    for row in rows {
+        let speech_seconds: f64 = row.try_get("speech_seconds")?;
+        shows.push(ShowInfo {
+            show_name: row.try_get("show_name")?,
+            row_count: row.try_get("row_count")?,
+            first_timestamp: to_rfc3339(row.try_get("first_timestamp")?),
+            last_timestamp: to_rfc3339(row.try_get("last_timestamp")?),
+            language: row.try_get("language")?,
+            model: row.try_get("model")?,
+            speech_hours: speech_seconds / 3600.0,
+            last_heartbeat_at: to_rfc3339(row.try_get("last_heartbeat_at")?),
+            is_live: row.try_get("is_live")?,
+        });
+    }
+    Ok(shows)
+}
@@ -8,6 +8,9 @@ use serde::{Deserialize, Serialize};
 use std::str;
 use read_chunks::ReadExt;
 
+use crate::ingest::IngestControl;
+use crate::metrics::ShowMetrics;
+
 fn convert_to_i16_vec(buf: &[u8]) -> Vec<i16> {
     let mut vec = Vec::with_capacity(buf.len() / 2); // Allocate space for i16 values
     for chunk in buf.chunks_exact(2) {
@@ -32,7 +35,7 @@ pub struct Segment {
 }
 
 
-fn streaming_inner_loop(input_url: &str, target_sample_rate: i64, sample_size: usize, tx: &Sender<Option<Segment>>,is_live_stream: bool) -> Result<(), Box<dyn std::error::Error>>
+fn streaming_inner_loop(input_url: &str, target_sample_rate: i64, sample_size: usize, tx: &Sender<Option<Segment>>,is_live_stream: bool, control: Option<&IngestControl>) -> Result<(), Box<dyn std::error::Error>>
 {
     // Path to the input file
     //let input_file = "input.mp3"; // Replace with your file path
@@ -73,11 +76,52 @@ fn streaming_inner_loop(input_url: &str, target_sample_rate: i64, sample_size: u
 
     //let mut buffer = vec![0u8; sample_size*2];
 
+    match forward_chunks(&mut reader, sample_size, tx, is_live_stream, control) {
+        Ok(true) => {
+            info!("ingest stopped, killing ffmpeg");
+            ffmpeg_process.kill()?;
+            ffmpeg_process.wait()?;
+            return Ok(());
+        },
+        Ok(false) => {},
+        Err(e) => {
+            ffmpeg_process.kill()?;
+            ffmpeg_process.wait()?;
+            return Err(e);
+        },
+    }
 
+    // Wait for the child process to finish
+    let status = ffmpeg_process.wait()?;
+    error!("ffmpeg exited with status: {}", status);
+    if !status.success() {
+        return Err(format!("ffmpeg failed with a non-zero exit code {}", status.code().unwrap_or(-1)).into());
+    }
+
+    Ok(())
+}
+
+// send chunks of s16le from reader until it ends, true when stopped by control
+pub(crate) fn forward_chunks<R: Read>(reader: &mut R, sample_size: usize, tx: &Sender<Option<Segment>>, is_live_stream: bool, control: Option<&IngestControl>) -> Result<bool, Box<dyn std::error::Error>> {
     while let Some(chunk) = reader.read_chunks(sample_size*2).next_chunk() {
+        if let Some(control) = control {
+            if control.is_stopped() {
+                return Ok(true);
+            }
+            if control.is_paused() {
+                if is_live_stream {
+                    // a live stream can't wait, drop what is said while paused
+                    continue;
+                }
+                while control.is_paused() && !control.is_stopped() {
+                    sleep(std::time::Duration::from_millis(200));
+                }
+            }
+        }
+
         // don't allow live stream (url with unlimited duration) to be too backed up
         if is_live_stream && tx.is_full() {
-            panic!("Channel is full for livestream, transcribe thread not being able to catch up, aborting");
+            return Err("Channel is full for livestream, transcribe thread not being able to catch up, aborting".into());
         }
 
         trace!("{}",json!({"channel_size": tx.len()}).to_string());
@@ -86,19 +130,21 @@ fn streaming_inner_loop(input_url: &str, target_sample_rate: i64, sample_size: u
         tx.send(Some(Segment{ timestamp_millis, samples }))?;
 
     }
+    Ok(false)
+}
 
-    // Wait for the child process to finish
-    let status = ffmpeg_process.wait()?;
-    error!("ffmpeg exited with status: {}", status);
-    if !status.success() {
-        return Err(format!("ffmpeg failed with a non-zero exit code {}", status.code().unwrap_or(-1)).into());
-    }
-
-    Ok(())
+/// The vad thread reads until `None`, so it is sent after a failed stream too or the thread never finishes.
+pub fn end_stream_on_error(tx: &Sender<Option<Segment>>, result: Result<(), Box<dyn std::error::Error>>) -> Result<(), String> {
+    result.map_err(|e| {
+        // waits for room if the channel is full, the vad thread keeps reading
+        let _ = tx.send(None);
+        e.to_string()
+    })
 }
 
 
-pub fn streaming_url(input_url: &str, target_sample_rate: i64, sample_size: usize,tx: &Sender<Option<Segment>>) -> Result<(), Box<dyn std::error::Error>>
+// control is checked between chunks to pause or stop the stream
+pub fn streaming_url(input_url: &str, target_sample_rate: i64, sample_size: usize,tx: &Sender<Option<Segment>>,metrics: Option<&ShowMetrics>,control: Option<&IngestControl>) -> Result<(), Box<dyn std::error::Error>>
 {
 
     // Run ffmpeg to get raw PCM (s16le) data at 16kHz
@@ -129,17 +175,26 @@ pub fn streaming_url(input_url: &str, target_sample_rate: i64, sample_size: usiz
     // Check if duration exists and print it
     if let Some(duration) = ffprobe_output.format.duration {
         debug!("Duration: {} seconds", duration);
-        streaming_inner_loop(input_url, target_sample_rate, sample_size, &tx, false)?;
+        streaming_inner_loop(input_url, target_sample_rate, sample_size, &tx, false, control)?;
 
         // Send none to signal the end of the stream
         tx.send(None)?;
     } else {
         info!("No duration found, assuming stream is infinite and will restart on stream stop");
         loop {
-            streaming_inner_loop(input_url, target_sample_rate, sample_size, &tx,true)?;
+            streaming_inner_loop(input_url, target_sample_rate, sample_size, &tx,true, control)?;
+            if control.map(|control| control.is_stopped()).unwrap_or(false) {
+                break;
+            }
             warn!("stream_stopped, restarting");
+            if let Some(metrics) = metrics {
+                metrics.inc_ffmpeg_restarts();
+            }
             sleep(std::time::Duration::from_millis(500));
         }
+
+        // Send none to signal the end of the stream
+        tx.send(None)?;
     }
 
     Ok(())
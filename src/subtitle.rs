@@ -0,0 +1,51 @@
+use crate::vad_processor::TranscriptSegment;
+
+fn format_srt_timestamp(ms: i64) -> String {
+    let ms = ms.max(0);
+    format!("{:02}:{:02}:{:02},{:03}", ms / 3_600_000, (ms / 60_000) % 60, (ms / 1000) % 60, ms % 1000)
+}
+
+fn format_vtt_timestamp(ms: i64) -> String {
+    let ms = ms.max(0);
+    format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, (ms / 60_000) % 60, (ms / 1000) % 60, ms % 1000)
+}
+
+pub fn to_srt(segments: &[TranscriptSegment]) -> String {
+    let mut srt = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        srt.push_str(&format!("{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            format_srt_timestamp(segment.start_ms),
+            format_srt_timestamp(segment.end_ms),
+            segment.text.trim()));
+    }
+    srt
+}
+
+pub fn to_vtt(segments: &[TranscriptSegment]) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for segment in segments {
+        vtt.push_str(&format!("{} --> {}\n{}\n\n",
+            format_vtt_timestamp(segment.start_ms),
+            format_vtt_timestamp(segment.end_ms),
+            segment.text.trim()));
+    }
+    vtt
+}
+
+pub fn to_text(segments: &[TranscriptSegment]) -> String {
+    let mut text = String::new();
+    for segment in segments {
+        text.push_str(segment.text.trim());
+        text.push('\n');
+    }
+    text
+}
+
+/// Segments with the translation in place of the text, for subtitles in english.
+pub fn with_translation(segments: Vec<TranscriptSegment>) -> Vec<TranscriptSegment> {
+    segments.into_iter().map(|segment| TranscriptSegment {
+        text: segment.translation.clone().unwrap_or_default(),
+        ..segment
+    }).collect()
+}
@@ -1,33 +1,44 @@
 use crossbeam::channel::{bounded, unbounded, Receiver};
 use hound::{self};
-use log::{debug, trace};
-use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use log::{debug, error, trace, warn};
 //use sqlx::sqlite::{SqliteConnectOptions};
-use sqlx::{pool, Pool, Postgres};
-use sqlx::ConnectOptions;
+use sqlx::{Pool, Postgres};
 use ringbuffer::{AllocRingBuffer, RingBuffer};
-use tokio::runtime::Runtime;
 
-use crate::config::DatabaseConfig;
-use crate::download_utils::{get_whisper_model, get_silero_model};
-use crate::key_ring_utils::get_password;
+use crate::db::{init_db_from_config, insert_transcript, NewTranscript};
+use crate::download_utils::{get_filename_from_url, get_whisper_model, get_silero_model};
+use crate::shows::{register_show, show_heartbeat, SHOW_HEARTBEAT_SECONDS};
+use crate::ingest::{IngestControl, IngestRequest};
+use crate::metrics::ShowMetrics;
+use crate::decoding::DecodingConfig;
+use crate::language::LanguageDetector;
+use crate::diarization::{Diarizer, SpeakerEmbedder};
+use crate::postprocess::PostProcessConfig;
+use crate::filter::{FilterAction, FilterConfig, SegmentFilter};
+use crate::prompt::{PromptConfig, ShowPrompt};
+use crate::audio_archive::{AudioArchive, TranscriptAudio};
 use crate::runtime_utils::{get_runtime};
 use crate::streaming::Segment;
-use crate::web::TranscribeWebServer;
-use crate::{config::Config, streaming::streaming_url, vad::VoiceActivityDetector};
-use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters, WhisperState};
+use crate::web::{bind_listener, default_web_addr, start_metrics_server, TranscribeWebServer};
+use crate::{config::Config, streaming::{end_stream_on_error, streaming_url}, vad::VoiceActivityDetector};
+use whisper_rs::{FullParams, WhisperContext, WhisperContextParameters, WhisperState, WhisperToken};
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time::Instant;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use zhconv::{zhconv, Variant};
-
 use std::thread::available_parallelism;
 
 use chrono::{TimeZone, Utc};
 
 
+use crate::convert_file_to_wave;
 use crate::record_audio::record_from_mic;
 
 
@@ -46,6 +57,10 @@ impl SpeechTag {
 }
 
 pub const TARGET_SAMPLE_RATE: i64 = 16000;
+// segments are padded with silence to this for the translation pass
+const MIN_TRANSLATE_SAMPLES: usize = TARGET_SAMPLE_RATE as usize * 3 / 2;
+// whisper encodes audio 30 seconds at a time
+const WHISPER_WINDOW_SAMPLES: usize = TARGET_SAMPLE_RATE as usize * 30;
 pub const SAMPLE_SIZE: usize = 1024;
 
 
@@ -55,10 +70,10 @@ The VAD predicts speech in a chunk of Linear Pulse Code Modulation (LPCM) encode
 The model is trained using chunk sizes of 256, 512, and 768 samples for an 8000 hz sample rate. It is trained using chunk sizes of 512, 768, 1024 samples for a 16,000 hz sample rate.
 */
 
-fn process_with_vad<E,F>(rx: &Receiver<Option<Segment>>, input_callback: E, mut output_callback: F) -> Result<(), Box<dyn std::error::Error>>
+fn process_with_vad<E,F>(rx: &Receiver<Option<Segment>>, metrics: Option<&ShowMetrics>, input_callback: E, mut output_callback: F) -> Result<(), Box<dyn std::error::Error>>
 where
     E: FnOnce() + std::marker::Send,
-    F: FnMut(Option<i64>,&Vec<i16>) + std::marker::Send,
+    F: FnMut(Option<i64>,&Vec<i16>,f32) + std::marker::Send,
 {
     //let target_sample_rate: i32 = 16000;
 
@@ -93,6 +108,11 @@ where
 
             let mut has_speech_begin_timestamp: Option<i64> = None;
 
+            // probabilities of the chunks in buf
+            let mut probability_sum = 0.0f32;
+            let mut probability_count = 0usize;
+            let mean_probability = |sum: f32, count: usize| if count > 0 { sum / count as f32 } else { 0.0 };
+
             let mut model = get_vad().unwrap();
             for segment in rx {
                 if let Some(segment)=segment {
@@ -116,13 +136,17 @@ where
                         has_speech = false;
                     }
 
+                    if let Some(metrics) = metrics {
+                        metrics.observe_vad_chunk(rx.len(), samples.len() as f64 / TARGET_SAMPLE_RATE as f64, has_speech);
+                    }
+
                     assert!(prev_samples.len()<=TARGET_SAMPLE_RATE as usize);
                     match prev_tag {
                     SpeechTag::NoSpeech => {
                         if has_speech {
                             trace!("Transitioning from no speech to speech");
-                            // save the timestamp
-                            has_speech_begin_timestamp = Some(segment.timestamp_millis);
+                            // save the timestamp, the buffer starts with the previous samples kept before speech
+                            has_speech_begin_timestamp = Some(segment.timestamp_millis - prev_samples.len() as i64 * 1000 / TARGET_SAMPLE_RATE);
                             // add previous sample if it exists
                             //if let Some(prev_sample2) = &prev_sample {
                             if prev_samples.len() > 0 {
@@ -134,6 +158,8 @@ where
                             //}
                             // start to extend the buffer
                             buf.extend(&samples);
+                            probability_sum += probability;
+                            probability_count += 1;
                         } else {
                             // maybe reset silero state if no speech for too long
                             trace!("Still No Speech");
@@ -153,12 +179,18 @@ where
                             trace!("Continue to has speech");
                             // continue to extend the buffer
                             buf.extend(&samples);
+                            probability_sum += probability;
+                            probability_count += 1;
                         } else {
                             trace!("Transitioning from speech to no speech");
                             buf.extend(&samples);
+                            probability_sum += probability;
+                            probability_count += 1;
                             //save the buffer if not empty
-                            output_callback(has_speech_begin_timestamp,&buf);
+                            output_callback(has_speech_begin_timestamp,&buf,mean_probability(probability_sum, probability_count));
                             has_speech_begin_timestamp = None;
+                            probability_sum = 0.0;
+                            probability_count = 0;
                             buf.clear();
                             prev_samples.clear();
                         }
@@ -176,7 +208,7 @@ where
             debug!("End of stream");
 
             if buf.len() > 0 {
-                output_callback(has_speech_begin_timestamp,&buf);
+                output_callback(has_speech_begin_timestamp,&buf,mean_probability(probability_sum, probability_count));
                 has_speech_begin_timestamp = None;
                 buf.clear();
                 //num += 1;
@@ -211,74 +243,460 @@ pub fn save_buf_to_file(buf: &Vec<i16>, file_name: &PathBuf) {
 }
 
 
-fn transcribe(state: &mut WhisperState, params: &whisper_rs::FullParams, samples: &Vec<i16>) {
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WordTimestamp {
+    pub word: String,
+    pub start_ms: i64,
+    pub end_ms: i64,
+    // mean probability of the tokens in the word
+    pub probability: f32,
+}
 
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct SegmentConfidence {
+    // mean log probability of the text tokens, None for a segment without any
+    pub avg_logprob: Option<f32>,
+    pub min_token_prob: Option<f32>,
+    // of the whole clip, None for clips longer than whisper's 30 second window
+    pub no_speech_prob: Option<f32>,
+    // mean silero probability of the speech clip the segment came from
+    pub vad_probability: f32,
+}
 
-    // Create an audio buffer to hold the audio samples.
-    let mut audio = vec![0.0f32; samples.len().try_into().unwrap()];
+#[derive(Clone, Serialize)]
+pub struct TranscriptSegment {
+    // milliseconds relative to the start of the transcribed audio
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub text: String,
+    // same time base as start_ms
+    pub words: Vec<WordTimestamp>,
+    #[serde(flatten)]
+    pub confidence: SegmentConfidence,
+    // english from a second whisper pass with the translate task, when asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translation: Option<String>,
+    // detected for the speech clip the segment is from, with language = "auto"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_probability: Option<f32>,
+    // whisper's text when text has been post processed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_text: Option<String>,
+    // speaker_1, speaker_2 and so on within a show run or file, with [diarization]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speaker: Option<String>,
+}
 
-    whisper_rs::convert_integer_to_float_audio(&samples, &mut audio).expect("Conversion error");
+/// What is done to every speech clip besides transcribing it.
+#[derive(Clone, Copy)]
+pub struct ClipOptions<'a> {
+    // threads of the extra decode for the no speech probability, the same as the params
+    pub n_threads: usize,
+    // add an english translation to every segment
+    pub translate: bool,
+    // picks the language of every clip, for language = "auto"
+    pub detector: Option<&'a LanguageDetector>,
+    // labels the speaker of every segment, locked only while labelling
+    pub diarizer: Option<&'a Mutex<Diarizer>>,
+}
 
-    // Run the model.
-    state.full(params.clone(), &audio[..]).expect("failed to run model");
+impl Default for ClipOptions<'_> {
+    fn default() -> Self {
+        Self {
+            n_threads: default_num_transcribe_threads(),
+            translate: false,
+            detector: None,
+            diarizer: None,
+        }
+    }
+}
 
-    /*
+// a token, or a run of tokens that only make whole utf-8 characters together
+struct TokenPiece {
+    text: String,
+    start_ms: i64,
+    end_ms: i64,
+    probabilities: Vec<f32>,
+}
 
-    let language = config.language.clone();
+// letters and digits of scripts written with spaces between words, cjk is split per piece
+fn joins_word(c: char) -> bool {
+    (c.is_alphanumeric() && (c as u32) < 0x2E80) || c == '\''
+}
 
-    use this if wait until it finishes
-    let rt = get_runtime();
+/// Merge the tokens of a segment into words with their timings, `text` is the segment text.
+fn segment_words(state: &WhisperState, segment: i32, text: &str) -> Result<Vec<WordTimestamp>, Box<dyn std::error::Error>> {
+    let mut pieces: Vec<TokenPiece> = Vec::new();
+    // segment text not yet matched to a token
+    let mut rest = text;
+    // tokens cut in the middle of a character, their text is whatever comes before the next whole token
+    let mut pending: Option<TokenPiece> = None;
+
+    for i in 0..state.full_n_tokens(segment)? {
+        let data = state.full_get_token_data(segment, i)?;
+        let token_text = state.full_get_token_text(segment, i).ok();
+        // timestamps and other special tokens like [_BEG_] are not part of the text
+        if let Some(token_text) = &token_text {
+            if token_text.starts_with("[_") || token_text.starts_with("<|") {
+                continue;
+            }
+        }
+        // whisper token times are in 10 ms units
+        let (start_ms, end_ms, p) = (data.t0 * 10, data.t1 * 10, data.p);
+
+        match token_text {
+            Some(token_text) => {
+                // not found when the segment text was cut short, leave it as it is
+                let offset = rest.find(token_text.as_str());
+                if let Some(mut piece) = pending.take() {
+                    piece.text = rest[..offset.unwrap_or(0)].to_string();
+                    pieces.push(piece);
+                }
+                if let Some(offset) = offset {
+                    rest = &rest[offset + token_text.len()..];
+                }
+                pieces.push(TokenPiece { text: token_text, start_ms, end_ms, probabilities: vec![p] });
+            },
+            None => {
+                let piece = pending.get_or_insert_with(|| TokenPiece { text: String::new(), start_ms, end_ms, probabilities: Vec::new() });
+                piece.end_ms = end_ms;
+                piece.probabilities.push(p);
+            },
+        }
+    }
+    if let Some(mut piece) = pending.take() {
+        piece.text = rest.to_string();
+        pieces.push(piece);
+    }
+
+    let mut words: Vec<(String, i64, i64, Vec<f32>)> = Vec::new();
+    for piece in pieces {
+        let first = match piece.text.chars().next() {
+            Some(c) => c,
+            None => continue,
+        };
+        let continues_word = match words.last() {
+            Some((word, ..)) if !first.is_whitespace() => {
+                // punctuation sticks to the word before it
+                !first.is_alphanumeric() || (joins_word(first) && word.chars().last().map(joins_word).unwrap_or(false))
+            },
+            _ => false,
+        };
+        if continues_word {
+            let (word, _, end_ms, probabilities) = words.last_mut().unwrap();
+            word.push_str(&piece.text);
+            *end_ms = piece.end_ms;
+            probabilities.extend(piece.probabilities);
+        } else {
+            words.push((piece.text, piece.start_ms, piece.end_ms, piece.probabilities));
+        }
+    }
+
+    Ok(words.into_iter().filter_map(|(word, start_ms, end_ms, probabilities)| {
+        let word = word.trim().to_string();
+        if word.is_empty() {
+            return None;
+        }
+        let probability = probabilities.iter().sum::<f32>() / probabilities.len().max(1) as f32;
+        Some(WordTimestamp { word, start_ms, end_ms, probability })
+    }).collect())
+}
+
+// whisper.cpp keeps the no speech probability to itself, so decode the start of transcript
+// prompt again on the audio encoded by the last full() and read it from the logits
+fn no_speech_prob(ctx: &WhisperContext, state: &mut WhisperState, n_threads: usize) -> Result<f32, Box<dyn std::error::Error>> {
+    let mut prompt = vec![ctx.token_sot()];
+    if ctx.is_multilingual() {
+        prompt.push(ctx.token_lang(state.full_lang_id_from_state()?));
+        prompt.push(ctx.token_transcribe());
+    }
+    state.decode(&prompt, 0, n_threads)?;
+    let logits = state.get_logits()?;
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+    Ok((logits[ctx.token_nosp() as usize] - max).exp() / sum)
+}
 
-	// fetch the results
-	let num_segments = state
-		.full_n_segments()
-		.expect("failed to get number of segments");
-	for i in 0..num_segments {
-		let text = state
-			.full_get_segment_text(i)
-			.expect("failed to get segment");
-		let start_timestamp = state
-			.full_get_segment_t0(i)
-			.expect("failed to get segment start timestamp");
-		let end_timestamp = state
-			.full_get_segment_t1(i)
-			.expect("failed to get segment end timestamp");
-
-
-        // Get the current timestamp using chrono
-        let current_timestamp = Utc::now();
-
-        let line = json!({"start_timestamp":start_timestamp,
-            "end_timestamp":end_timestamp, "cur_ts": format!("{}",current_timestamp.to_rfc3339()), "text":text});
-        println!("{}", line);
-
-        // only convert to traditional chinese when saving to db
-        // output original in jsonl
-        let db_save_text = match language.as_str() {
-            "zh" | "yue" => {
-                zhconv(&text, Variant::ZhHant)
+// None when the segment has no text tokens
+fn segment_confidence(ctx: &WhisperContext, state: &WhisperState, segment: i32) -> Result<Option<(f32, f32)>, Box<dyn std::error::Error>> {
+    let mut logprob_sum = 0.0f32;
+    let mut min_token_prob = 1.0f32;
+    let mut count = 0;
+    for i in 0..state.full_n_tokens(segment)? {
+        let data = state.full_get_token_data(segment, i)?;
+        // timestamps and other special tokens
+        if data.id >= ctx.token_eot() {
+            continue;
+        }
+        logprob_sum += data.plog;
+        min_token_prob = min_token_prob.min(data.p);
+        count += 1;
+    }
+    if count == 0 {
+        return Ok(None);
+    }
+    Ok(Some((logprob_sum / count as f32, min_token_prob)))
+}
+
+fn transcribe(ctx: &WhisperContext, state: &mut WhisperState, params: &whisper_rs::FullParams, samples: &Vec<i16>, vad_probability: f32, options: &ClipOptions) -> Result<Vec<TranscriptSegment>, Box<dyn std::error::Error>> {
+
+
+    // Create an audio buffer to hold the audio samples.
+    let mut audio = vec![0.0f32; samples.len()];
+
+    whisper_rs::convert_integer_to_float_audio(&samples, &mut audio)?;
+
+    // decode with the language of this clip
+    let mut params = params.clone();
+    let detected = match options.detector {
+        Some(detector) => match detector.detect(state, &audio) {
+            Ok((language, probability)) => {
+                debug!("detected language {} ({:.2})", language, probability);
+                params.set_language(Some(language));
+                Some((language, probability))
             },
-            _ => {
-                text
+            Err(e) => {
+                warn!("failed to detect language: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Run the model.
+    state.full(params.clone(), &audio[..])?;
+
+    // fetch the results
+    let num_segments = state.full_n_segments()?;
+    // only the last window is left encoded, which is the whole clip up to 30 seconds
+    let no_speech_prob = if num_segments > 0 && samples.len() <= WHISPER_WINDOW_SAMPLES {
+        match no_speech_prob(ctx, state, options.n_threads) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                warn!("failed to get no speech probability: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut segments = Vec::with_capacity(num_segments as usize);
+    for i in 0..num_segments {
+        let text = match state.full_get_segment_text(i) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("skipping segment {}: {}", i, e);
+                continue;
             }
         };
-        if let Some(pool) = &pool {
-            rt.block_on(async {
-                let sql = r#"INSERT INTO transcripts (show_name,"timestamp", content) VALUES ($1, $2, $3)"#;
-                //eprint!("{}", sql);
-                sqlx::query(
-                    sql,
-                )
-                .bind(config.show_name.as_str())
-                .bind(current_timestamp)
-                .bind(db_save_text)
-                .execute(pool).await?;
-                Ok::<(), Box<dyn std::error::Error>>(())    
-            }).unwrap();
+        let words = match segment_words(state, i, &text) {
+            Ok(words) => words,
+            Err(e) => {
+                warn!("no word timestamps for segment {}: {}", i, e);
+                Vec::new()
+            }
+        };
+        let (avg_logprob, min_token_prob) = segment_confidence(ctx, state, i)?.unzip();
+        segments.push(TranscriptSegment {
+            // whisper timestamps are in 10 ms units
+            start_ms: state.full_get_segment_t0(i)? * 10,
+            end_ms: state.full_get_segment_t1(i)? * 10,
+            text,
+            words,
+            confidence: SegmentConfidence { avg_logprob, min_token_prob, no_speech_prob, vad_probability },
+            translation: None,
+            language: detected.map(|(language, _)| language.to_string()),
+            language_probability: detected.map(|(_, probability)| probability),
+            raw_text: None,
+            speaker: None,
+        });
+    }
+
+    if let Some(diarizer) = options.diarizer {
+        // before translating, segments may be split at speaker changes
+        segments = lock_diarizer(diarizer).label_segments(samples, segments);
+    }
+
+    if options.translate {
+        if detected.map(|(language, _)| language == "en").unwrap_or(false) {
+            for segment in segments.iter_mut() {
+                segment.translation = Some(segment.text.trim().to_string());
+            }
+        } else {
+            let whole_clip = segments.len() == 1;
+            translate_segments(state, &params, &audio, whole_clip, &mut segments)?;
         }
+    }
+
+    Ok(segments)
+}
 
-	}
-     */
+fn lock_diarizer(diarizer: &Mutex<Diarizer>) -> MutexGuard<'_, Diarizer> {
+    match diarizer.lock() {
+        Ok(diarizer) => diarizer,
+        Err(poisoned) => {
+            // the speakers are still usable after a panic in another worker
+            let guard = poisoned.into_inner();
+            diarizer.clear_poison();
+            guard
+        },
+    }
+}
+
+// translate each segment on its own audio so the translation lines up with the original text
+fn translate_segments(state: &mut WhisperState, params: &whisper_rs::FullParams, audio: &[f32], whole_clip: bool, segments: &mut [TranscriptSegment]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut translate_params = params.clone();
+    translate_params.set_translate(true);
+    translate_params.set_token_timestamps(false);
+    translate_params.set_single_segment(true);
+    translate_params.set_max_len(0);
+    // an empty prompt, the show prompt and context are in the original language
+    translate_params.set_tokens(&[]);
+
+    for segment in segments.iter_mut() {
+        let mut slice = if whole_clip {
+            audio.to_vec()
+        } else {
+            let start = (segment.start_ms * TARGET_SAMPLE_RATE / 1000).clamp(0, audio.len() as i64) as usize;
+            let end = (segment.end_ms * TARGET_SAMPLE_RATE / 1000).clamp(start as i64, audio.len() as i64) as usize;
+            audio[start..end].to_vec()
+        };
+        // whisper skips audio shorter than a second
+        if slice.len() < MIN_TRANSLATE_SAMPLES {
+            slice.resize(MIN_TRANSLATE_SAMPLES, 0.0);
+        }
+        state.full(translate_params.clone(), &slice)?;
+        let mut translation = String::new();
+        for i in 0..state.full_n_segments()? {
+            translation.push_str(&state.full_get_segment_text(i)?);
+        }
+        segment.translation = Some(translation.trim().to_string());
+    }
+    Ok(())
+}
+
+/// Words of a segment relative to the start of the segment, which is the timestamp of its transcript.
+pub fn words_for_db(postprocess: &PostProcessConfig, language: &str, segment: &TranscriptSegment) -> Vec<WordTimestamp> {
+    segment.words.iter().map(|word| WordTimestamp {
+        word: postprocess.convert_chinese(language, word.word.clone()),
+        start_ms: word.start_ms - segment.start_ms,
+        end_ms: word.end_ms - segment.start_ms,
+        probability: word.probability,
+    }).collect()
+}
+
+pub fn default_num_transcribe_threads() -> usize {
+    // get 4 or the number of cpus if less than 4
+    let default_parallelism_approx = available_parallelism().unwrap().get();
+    *[default_parallelism_approx,4].iter().min().unwrap_or(&1)
+}
+
+pub fn build_params<'a>(language: &'a str, n_threads: usize, decoding: &DecodingConfig) -> FullParams<'a, 'a> {
+    // Create a params object for running the model.
+    // The number of past samples to consider defaults to 0.
+    let mut params = FullParams::new(decoding.sampling_strategy());
+
+    // Edit params as needed.
+    // Set the number of threads to use to 4.
+    params.set_n_threads(n_threads as i32);
+    // Enable translation.
+    params.set_translate(false);
+    // Set the language to translate to to English.
+    params.set_language(Some(language));
+    // Disable anything that prints to stdout.
+    params.set_print_special(false);
+    params.set_debug_mode(false);
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_timestamps(false);
+    // Enable token level timestamps
+    params.set_token_timestamps(true);
+    params.set_n_max_text_ctx(64);
+    decoding.apply(&mut params);
+
+    params
+}
+
+pub fn load_whisper_context(model_download_url: &str) -> Result<WhisperContext, Box<dyn std::error::Error>> {
+    // Load a context and model.
+    let context_param = WhisperContextParameters::default();
+
+    let model_path = get_whisper_model(model_download_url)?;
+
+    let ctx = WhisperContext::new_with_params(
+        model_path.to_str().unwrap(),
+        context_param,
+    )?;
+    Ok(ctx)
+}
+
+/// Run audio samples through vad and whisper, segment times are relative to the start of the samples.
+///
+/// `options` is applied to every speech clip, `progress` is called with the fraction of the audio
+/// processed so far.
+pub fn transcribe_samples<P>(ctx: &WhisperContext, state: &mut WhisperState, params: &FullParams, samples: &[i16], options: ClipOptions, mut progress: P) -> Result<Vec<TranscriptSegment>, Box<dyn std::error::Error>>
+where
+    P: FnMut(f32) + std::marker::Send,
+{
+    let total_ms = (samples.len() as i64 * 1000 / TARGET_SAMPLE_RATE).max(1);
+    let (tx, rx) = bounded::<Option<Segment>>((TARGET_SAMPLE_RATE*60).try_into().unwrap());
+
+    let mut segments = Vec::new();
+    let mut error: Option<String> = None;
+
+    process_with_vad(&rx, None,
+        || {
+            for (i, chunk) in samples.chunks(SAMPLE_SIZE).enumerate() {
+                let timestamp_millis = (i * SAMPLE_SIZE) as i64 * 1000 / TARGET_SAMPLE_RATE;
+                tx.send(Some(Segment{ timestamp_millis, samples: chunk.to_vec() })).unwrap();
+            }
+            tx.send(None).unwrap();
+        },
+        |timestamp_millis, buf: &Vec<i16>, vad_probability| {
+            if error.is_some() {
+                return;
+            }
+            let offset_ms = timestamp_millis.unwrap_or(0);
+            match transcribe(ctx, state, params, buf, vad_probability, &options) {
+                Ok(result) => {
+                    segments.extend(result.into_iter().map(|segment| TranscriptSegment {
+                        start_ms: offset_ms + segment.start_ms,
+                        end_ms: offset_ms + segment.end_ms,
+                        text: segment.text,
+                        words: segment.words.into_iter().map(|word| WordTimestamp {
+                            start_ms: offset_ms + word.start_ms,
+                            end_ms: offset_ms + word.end_ms,
+                            ..word
+                        }).collect(),
+                        confidence: segment.confidence,
+                        translation: segment.translation,
+                        language: segment.language,
+                        language_probability: segment.language_probability,
+                        raw_text: segment.raw_text,
+                        speaker: segment.speaker,
+                    }));
+                },
+                Err(e) => error = Some(e.to_string()),
+            }
+            let processed_ms = offset_ms + buf.len() as i64 * 1000 / TARGET_SAMPLE_RATE;
+            progress((processed_ms as f32 / total_ms as f32).min(1.0));
+        })?;
+
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+    progress(1.0);
+    Ok(segments)
+}
+
+pub fn transcribe_file<P>(ctx: &WhisperContext, state: &mut WhisperState, params: &FullParams, input_file: &str, options: ClipOptions, progress: P) -> Result<Vec<TranscriptSegment>, Box<dyn std::error::Error>>
+where
+    P: FnMut(f32) + std::marker::Send,
+{
+    let samples = convert_file_to_wave(input_file, TARGET_SAMPLE_RATE as i32)?;
+    transcribe_samples(ctx, state, params, &samples, options, progress)
 }
 
 fn get_vad() -> Result<VoiceActivityDetector, Box<dyn std::error::Error>> {
@@ -301,7 +719,7 @@ pub fn stream_to_file(config: Config) -> Result<(), Box<dyn std::error::Error>>{
     std::fs::create_dir_all(&folder).expect("failed to create folder to save files");
 
     let mut num = 1;
-    let closure_annotated = |_has_speech_begin_timestamp,buf: &Vec<i16>| {
+    let closure_annotated = |_has_speech_begin_timestamp,buf: &Vec<i16>,_vad_probability| {
         let file_name = folder.join(format!("predict.stream.speech.{}.wav", format!("{:0>3}",num)));
         save_buf_to_file(&buf, &file_name);
         num += 1;
@@ -314,9 +732,9 @@ pub fn stream_to_file(config: Config) -> Result<(), Box<dyn std::error::Error>>{
 
             let url = config.url.as_ref().expect("url is required when source is url");
 
-            process_with_vad(&rx,
+            process_with_vad(&rx, None,
                 || {
-                   streaming_url(url,TARGET_SAMPLE_RATE,SAMPLE_SIZE,&tx).unwrap();
+                   streaming_url(url,TARGET_SAMPLE_RATE,SAMPLE_SIZE,&tx,None,None).unwrap();
                },
                closure_annotated)?;
 
@@ -324,7 +742,7 @@ pub fn stream_to_file(config: Config) -> Result<(), Box<dyn std::error::Error>>{
         },
         crate::config::Source::Microphone => {
             let (tx, rx) = unbounded::<Option<Segment>>().try_into().unwrap();
-            process_with_vad(&rx,
+            process_with_vad(&rx, None,
                 || {
                     record_from_mic(&tx,SAMPLE_SIZE).unwrap();
                 },
@@ -340,10 +758,10 @@ pub fn stream_to_file(config: Config) -> Result<(), Box<dyn std::error::Error>>{
                 panic!("database config is required when source is web for pulling data for web");
             };
 
-            process_with_vad(&rx,
+            process_with_vad(&rx, None,
                 || {
                     rt.block_on(async {
-                        TranscribeWebServer::new(5002,tx.clone(),pool).start_webserver().await
+                        TranscribeWebServer::new(default_web_addr(),Some(tx.clone()),pool).start_webserver().await
                     });
                 },
                 closure_annotated)?;
@@ -353,215 +771,508 @@ pub fn stream_to_file(config: Config) -> Result<(), Box<dyn std::error::Error>>{
     Ok(())
 }
 
-fn init_db_from_config(rt: &Runtime,database_config: &DatabaseConfig) -> Result<Pool<sqlx::Postgres>, Box<dyn std::error::Error>> {
-    let database_password = get_password(&database_config.database_password_key)?;
-    //println!("My password is '{}'", database_password);
-
-    rt.block_on(async {
-        let ssl_mode = match database_config.require_ssl {
-            true => sqlx::postgres::PgSslMode::Require,
-            _ => sqlx::postgres::PgSslMode::Prefer
-        };
-
-        let options = PgConnectOptions::new()
-            .ssl_mode(ssl_mode)
-            .host(&database_config.database_host)
-            .port(database_config.database_port.unwrap_or(5432))
-            .database(database_config.database_name.as_str())
-            .username(database_config.database_user.as_str())
-            .password(database_password.as_str())
-            .log_statements(log::LevelFilter::Trace);
-
-        let pool2 = PgPoolOptions::new().connect_with(options).await?;
-        sqlx::query(r#"CREATE TABLE IF NOT EXISTS transcripts (
-            id bigserial PRIMARY KEY,
-            show_name varchar(255) NOT NULL,
-            "timestamp" TIMESTAMP WITHOUT TIME ZONE NOT NULL,
-            content TEXT NOT NULL
-            );"#
-            ).execute(&pool2).await?;
-        sqlx::query(r#"create index if not exists transcript_show_name_idx ON transcripts (show_name);"#
-            ).execute(&pool2).await?;
-
-        Ok::<Pool<Postgres>, Box<dyn std::error::Error>>(pool2)
-    })
+/// What happens to transcripts besides printing them.
+pub struct TranscriptOutput<'a> {
+    pub pool: Option<Pool<Postgres>>,
+    pub audio_archive_dir: Option<&'a String>,
+    pub filter_config: Option<&'a FilterConfig>,
+    // chinese conversion and the other text transforms, the default converts zh and yue to zh-Hant
+    pub postprocess: Option<&'a PostProcessConfig>,
+    // speaker labels with [diarization], speakers are told apart until the show stops
+    pub speakers: Option<Arc<SpeakerEmbedder>>,
 }
 
-// also saves to db if database_name is provided in config
-pub fn transcribe_url(config: Config,num_transcribe_threads: Option<usize>,model_download_url: &str) -> Result<(), Box<dyn std::error::Error>> {
-
-    let rt = get_runtime();
-    //eprintln!("transcribe_url");
+/// A speech clip cut by vad with the params it is transcribed with.
+struct SpeechClip<'a> {
+    // order the clip was cut in
+    seq: u64,
+    timestamp_millis: Option<i64>,
+    buf: Vec<i16>,
+    vad_probability: f32,
+    params: FullParams<'a, 'a>,
+    // prompt and carried over context, set on params when the clip is transcribed
+    context_tokens: Option<Vec<WhisperToken>>,
+}
 
-    let source = &config.source;
-    let mut pool: Option<Pool<_>> = None;
+/// Transcribes the speech clips cut by vad for one show, prints them as jsonl and
+/// saves them to the database when there is one.
+///
+/// Shared by the transcribe workers, each with its own whisper state.
+struct SpeechTranscriber<'a> {
+    ctx: &'a WhisperContext,
+    show_name: String,
+    language: String,
+    params: Mutex<FullParams<'a, 'a>>,
+    pool: Option<Pool<Postgres>>,
+    audio_archive: Option<AudioArchive>,
+    metrics: Arc<ShowMetrics>,
+    filter: Mutex<Option<SegmentFilter>>,
+    prompt: Mutex<ShowPrompt>,
+    // of the params
+    n_threads: usize,
+    // save an english translation next to the original
+    translate: bool,
+    // set for language = "auto"
+    detector: Option<LanguageDetector>,
+    diarizer: Option<Mutex<Diarizer>>,
+    postprocess: PostProcessConfig,
+}
 
-    if let Some(database_config) = &config.database_config {
-        pool = Some(init_db_from_config(rt,database_config)?);
+impl<'a> SpeechTranscriber<'a> {
+    fn new(ctx: &'a WhisperContext, show_name: &str, language: &'a str, params: FullParams<'a, 'a>, metrics: Arc<ShowMetrics>, output: TranscriptOutput, prompt: Option<&PromptConfig>) -> Self {
+        let postprocess = output.postprocess.cloned().unwrap_or_default();
+        Self {
+            ctx,
+            show_name: show_name.to_string(),
+            language: language.to_string(),
+            params: Mutex::new(params),
+            pool: output.pool,
+            audio_archive: output.audio_archive_dir.map(AudioArchive::new),
+            metrics,
+            filter: Mutex::new(output.filter_config.map(|filter_config| SegmentFilter::new(filter_config, &postprocess))),
+            prompt: Mutex::new(ShowPrompt::new(&prompt.cloned().unwrap_or_default())),
+            n_threads: default_num_transcribe_threads(),
+            translate: false,
+            detector: None,
+            diarizer: output.speakers.map(|speakers| Mutex::new(Diarizer::new(speakers))),
+            postprocess,
+        }
     }
 
+    /// Transcribe and save one clip with `state`, for a single worker.
+    fn transcribe_speech(&self, state: &mut WhisperState, timestamp_millis: Option<i64>, buf: &[i16], vad_probability: f32) {
+        let clip = self.clip(0, timestamp_millis, buf, vad_probability);
+        let segments = self.transcribe_clip_or_skip(state, &clip);
+        let segments = self.label_speakers(Some(state), &clip, segments);
+        self.save(&clip, segments);
+    }
 
-    // Load a context and model.
-    let context_param = WhisperContextParameters::default();
-
-    let download_url = model_download_url;
-
-    let model_path = get_whisper_model(download_url)?;
-
-
-    let ctx = WhisperContext::new_with_params(
-        model_path.to_str().unwrap(),
-        context_param,
-    ).expect("failed to load model");
-
-
-
-    // Create a params object for running the model.
-    // The number of past samples to consider defaults to 0.
-    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 5 });
-
+    // called in the order clips are cut, with one worker the clip before has been saved by then
+    fn clip(&self, seq: u64, timestamp_millis: Option<i64>, buf: &[i16], vad_probability: f32) -> SpeechClip<'a> {
+        let mut prompt = self.prompt.lock().unwrap();
+        let mut params = self.params.lock().unwrap();
+        // picks up changes to the vocabulary file
+        prompt.apply(&mut params);
+        // prompt tokens take over from the initial prompt
+        let context_tokens = prompt.context_tokens(self.ctx, timestamp_millis);
+        SpeechClip {
+            seq,
+            timestamp_millis,
+            buf: buf.to_vec(),
+            vad_probability,
+            params: params.clone(),
+            context_tokens,
+        }
+    }
 
-    let n_threads = match num_transcribe_threads {
-        Some(n) => n,
-        None => {
-            // get 4 or the number of cpus if less than 4
-            let default_parallelism_approx = available_parallelism().unwrap().get();
-            *[default_parallelism_approx,4].iter().min().unwrap_or(&1)
+    fn transcribe_clip(&self, state: &mut WhisperState, clip: &SpeechClip) -> Result<Vec<TranscriptSegment>, Box<dyn std::error::Error>> {
+        let mut params = clip.params.clone();
+        if let Some(context_tokens) = &clip.context_tokens {
+            params.set_tokens(context_tokens);
         }
-    };
+        // speakers are labelled by label_speakers in the order clips are cut
+        let options = ClipOptions {
+            n_threads: self.n_threads,
+            translate: self.translate,
+            detector: self.detector.as_ref(),
+            diarizer: None,
+        };
+        let transcribe_start = Instant::now();
+        let segments = transcribe(self.ctx, state, &params, &clip.buf, clip.vad_probability, &options)?;
+        self.metrics.observe_transcription(transcribe_start.elapsed().as_secs_f64(), clip.buf.len() as f64 / TARGET_SAMPLE_RATE as f64, segments.len());
+        Ok(segments)
+    }
 
+    // a failed clip is saved as nothing instead of stopping the show
+    fn transcribe_clip_or_skip(&self, state: &mut WhisperState, clip: &SpeechClip) -> Vec<TranscriptSegment> {
+        self.transcribe_clip(state, clip).unwrap_or_else(|e| {
+            error!("failed to transcribe speech clip {}: {}", clip.seq, e);
+            Vec::new()
+        })
+    }
 
-    //assert_eq!(n_threads,1);
+    // called in the order clips are cut, so speakers are numbered the same with any number of workers,
+    // `state` translates the pieces of segments split at a speaker change
+    fn label_speakers(&self, state: Option<&mut WhisperState>, clip: &SpeechClip, segments: Vec<TranscriptSegment>) -> Vec<TranscriptSegment> {
+        let diarizer = match &self.diarizer {
+            Some(diarizer) => diarizer,
+            None => return segments,
+        };
+        let mut segments = lock_diarizer(diarizer).label_segments(&clip.buf, segments);
+        if let (true, Some(state)) = (self.translate, state) {
+            if let Err(e) = self.translate_pieces(state, clip, &mut segments) {
+                warn!("failed to translate segments split at a speaker change: {}", e);
+            }
+        }
+        segments
+    }
 
-    // Edit params as needed.
-    // Set the number of threads to use to 4.
-    params.set_n_threads(n_threads as i32);
-    // Enable translation.
-    params.set_translate(false);
-    // Set the language to translate to to English.
-    params.set_language(Some(config.language.as_str()));
-    // Disable anything that prints to stdout.
-    params.set_print_special(false);
-    params.set_debug_mode(false);
-    params.set_print_progress(false);
-    params.set_print_realtime(false);
-    params.set_print_timestamps(false);
-    // Enable token level timestamps
-    params.set_token_timestamps(true);
-    params.set_n_max_text_ctx(64);
+    fn translate_pieces(&self, state: &mut WhisperState, clip: &SpeechClip, segments: &mut [TranscriptSegment]) -> Result<(), Box<dyn std::error::Error>> {
+        if segments.iter().all(|segment| segment.translation.is_some()) {
+            return Ok(());
+        }
+        let mut audio = vec![0.0f32; clip.buf.len()];
+        whisper_rs::convert_integer_to_float_audio(&clip.buf, &mut audio)?;
+        let mut params = clip.params.clone();
+        for segment in segments.iter_mut().filter(|segment| segment.translation.is_none()) {
+            // detected for the clip with language = "auto"
+            let language = segment.language.as_deref().and_then(whisper_rs::get_lang_id).and_then(whisper_rs::get_lang_str);
+            if language == Some("en") {
+                segment.translation = Some(segment.text.trim().to_string());
+                continue;
+            }
+            if language.is_some() {
+                params.set_language(language);
+            }
+            translate_segments(state, &params, &audio, false, std::slice::from_mut(segment))?;
+        }
+        Ok(())
+    }
 
-    //let mut file = File::create("transcript.jsonl").expect("failed to create file");
-    
-    
-    let language = config.language.clone();
+    // called in the order clips are cut
+    fn save(&self, clip: &SpeechClip, segments: Vec<TranscriptSegment>) {
+        let rt = get_runtime();
+        let (timestamp_millis, buf) = (clip.timestamp_millis, &clip.buf);
 
+        self.prompt.lock().unwrap().remember(timestamp_millis, buf.len() as i64 * 1000 / TARGET_SAMPLE_RATE, &segments);
 
-    let mut state = ctx.create_state().expect("failed to create key");
+        // filtered first, so a clip with every segment dropped isn't archived
+        let mut kept = Vec::with_capacity(segments.len());
+        for segment in segments {
+            // detected for this clip with language = "auto"
+            let language = segment.language.clone().unwrap_or_else(|| self.language.clone());
 
+            // the same text is printed and saved, whisper's own is kept as raw_text
+            let words = words_for_db(&self.postprocess, &language, &segment);
+            let db_save_text = self.postprocess.process(&language, &segment.text);
 
-    //let whisper_wrapper_ref = RefCell::new(whisper_wrapper);
-    //let whisper_wrapper_ref2 = &whisper_wrapper;
-    let closure_annotated = |timestamp_millis,buf: &Vec<i16>| {
+            let filter_reason = match &mut *self.filter.lock().unwrap() {
+                Some(filter) => filter.check(&db_save_text, &language, segment.end_ms - segment.start_ms).map(|reason| (reason, filter.action())),
+                None => None,
+            };
+            if let Some((reason, action)) = filter_reason {
+                debug!("filtered {} segment: {}", reason.as_str(), db_save_text);
+                self.metrics.inc_filtered(reason.as_str());
+                // don't prompt the next clip with it
+                self.prompt.lock().unwrap().reset_context();
+                if action == FilterAction::Drop {
+                    continue;
+                }
+            }
+            let filter_reason = filter_reason.map(|(reason, _)| reason.as_str());
+            kept.push((segment, language, words, db_save_text, filter_reason));
+        }
 
-        let mut params2 = params.clone();
-        let language2 = language.clone();
-        let show_name2 = config.show_name.clone();
-        // safe to clone https://github.com/launchbadge/sqlx/discussions/917
-        let pool2 = pool.clone();
-        let rt2= rt.clone();
-        //let buf2 = buf.clone();
-        params2.set_segment_callback_safe( move |data: whisper_rs::SegmentCallbackData| {
-            //let buf3 = buf2.clone();
+        let clip_path = match &self.audio_archive {
+            Some(audio_archive) if !kept.is_empty() => {
+                let clip_timestamp = timestamp_millis.unwrap_or_else(|| Utc::now().timestamp_millis());
+                match audio_archive.save_clip(self.show_name.as_str(), clip_timestamp, buf) {
+                    Ok(path) => Some(path),
+                    Err(e) => {
+                        error!("failed to archive audio: {}", e);
+                        None
+                    }
+                }
+            },
+            _ => None,
+        };
 
+        for (segment, language, words, db_save_text, filter_reason) in kept {
 
             let calculated_start_timestamp_obj = if let Some(timestamp_millis) = timestamp_millis{
-              Some(Utc.timestamp_millis_opt(timestamp_millis+data.start_timestamp).unwrap())
+              Some(Utc.timestamp_millis_opt(timestamp_millis+segment.start_ms).unwrap())
             }else{
                 None
             };
 
-            let calculated_start_timestamp = match calculated_start_timestamp_obj {
-                Some(ts) => Some(ts.to_rfc3339()),
-                None => None
-            };
+            let calculated_start_timestamp = calculated_start_timestamp_obj.map(|ts| ts.to_rfc3339());
 
-            //let calculated_end_timestamp = Utc.timestamp_millis_opt(timestamp_millis+data.end_timestamp);
-
-            let line = json!({"start_timestamp":data.start_timestamp,
-                "end_timestamp":data.end_timestamp, "cur_ts": calculated_start_timestamp, "text":data.text});
+            let line = json!({"start_timestamp":segment.start_ms,
+                "end_timestamp":segment.end_ms, "cur_ts": calculated_start_timestamp, "text":db_save_text, "raw_text":segment.text, "translation": segment.translation, "language": language, "language_probability": segment.language_probability, "speaker": segment.speaker, "filter_reason": filter_reason});
             println!("{}", line);
 
-            // only convert to traditional chinese when saving to db
-            // output original in jsonl
-            let db_save_text = match language2.as_str() {
-                "zh" | "yue" => {
-                    zhconv(&data.text, Variant::ZhHant)
-                },
-                _ => {
-                    data.text
-                }
-            };
-            if let Some(pool) = &pool2 {
+            let audio = clip_path.as_ref().map(|path| TranscriptAudio {
+                path: path.clone(),
+                start_ms: segment.start_ms,
+                end_ms: segment.end_ms,
+            });
+            if let Some(pool) = &self.pool {
                 // need to fallback to current timestamp if calculated_start_timestamp is None
                 let current_timestamp_db_save = match calculated_start_timestamp_obj {
                     Some(ts) => ts,
                     None => Utc::now()
                 };
-                rt2.block_on(async {
-                    let sql = r#"INSERT INTO transcripts (show_name,"timestamp", content) VALUES ($1, $2, $3)"#;
-                    //eprint!("{}", sql);
-                    sqlx::query(
-                        sql,
-                    )
-                    .bind(show_name2.as_str())
-                    .bind(current_timestamp_db_save)
-                    .bind(db_save_text)
-                    .execute(pool).await?;
-                    Ok::<(), Box<dyn std::error::Error>>(())    
-                }).unwrap();
+                let insert_start = Instant::now();
+                let result = rt.block_on(async {
+                    insert_transcript(pool, &NewTranscript {
+                        show_name: self.show_name.as_str(),
+                        timestamp: current_timestamp_db_save,
+                        content: &db_save_text,
+                        raw_content: &segment.text,
+                        duration_ms: segment.end_ms - segment.start_ms,
+                        audio: audio.as_ref(),
+                        words: &words,
+                        confidence: &segment.confidence,
+                        filter_reason,
+                        translation: segment.translation.as_deref(),
+                        language: &language,
+                        language_probability: segment.language_probability,
+                        speaker: segment.speaker.as_deref(),
+                    }).await
+                });
+                self.metrics.observe_db_insert(insert_start.elapsed().as_secs_f64(), result.is_ok());
+                if let Err(e) = result {
+                    error!("failed to save transcript: {}", e);
+                }
             }
+        }
+    }
+}
 
+/// Run `run` with a callback for the speech clips it cuts, which are transcribed by `workers`
+/// threads with a whisper state each and saved in the order they were cut.
+fn transcribe_with_workers<R>(transcriber: &SpeechTranscriber, workers: usize, run: R) -> Result<(), Box<dyn std::error::Error>>
+where
+    R: FnOnce(&mut (dyn FnMut(Option<i64>, &Vec<i16>, f32) + Send)) -> Result<(), Box<dyn std::error::Error>>,
+{
+    if workers <= 1 {
+        let mut state = transcriber.ctx.create_state()?;
+        return run(&mut |timestamp_millis, buf: &Vec<i16>, vad_probability| {
+            transcriber.transcribe_speech(&mut state, timestamp_millis, buf, vad_probability);
         });
+    }
 
-        transcribe(&mut state, &params2, &buf);
-
+    // all states up front, so a failed one stops nothing that is already running
+    let states = (0..workers).map(|_| transcriber.ctx.create_state()).collect::<Result<Vec<_>, _>>()?;
+    // for the saving thread to translate segments split at a speaker change
+    let mut saver_state = if transcriber.translate && transcriber.diarizer.is_some() {
+        Some(transcriber.ctx.create_state()?)
+    } else {
+        None
     };
+    thread::scope(|s| {
+        // blocks vad while every worker is busy, like a single worker would
+        let (clip_tx, clip_rx) = bounded::<SpeechClip>(workers);
+        let (done_tx, done_rx) = unbounded::<(SpeechClip, Vec<TranscriptSegment>)>();
+        for mut state in states {
+            let (clip_rx, done_tx) = (clip_rx.clone(), done_tx.clone());
+            s.spawn(move || {
+                for clip in clip_rx {
+                    // every clip is sent back, or the clips after it are never saved
+                    let segments = std::panic::catch_unwind(AssertUnwindSafe(|| transcriber.transcribe_clip_or_skip(&mut state, &clip)))
+                        .unwrap_or_else(|_| {
+                            error!("transcribing speech clip {} panicked", clip.seq);
+                            Vec::new()
+                        });
+                    if done_tx.send((clip, segments)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // sending fails instead of blocking once every worker is gone
+        drop(clip_rx);
+        drop(done_tx);
 
-    match source {
-        crate::config::Source::Url => {
-            let url = config.url.as_ref().expect("url is required when source is url");
-            let (tx, rx) = bounded::<Option<Segment>>((TARGET_SAMPLE_RATE*60).try_into().unwrap());
-            process_with_vad(&rx,
-                || {
-                    streaming_url(url,TARGET_SAMPLE_RATE,SAMPLE_SIZE,&tx).unwrap();
-                },
-                closure_annotated)?;
-        },
-        crate::config::Source::Microphone => {
-            let (tx, rx) = unbounded::<Option<Segment>>().try_into().unwrap();
-            process_with_vad(&rx,
-                || {
-                    record_from_mic(&tx,SAMPLE_SIZE).unwrap();
-                },
-                closure_annotated)?;
-        },
-        crate::config::Source::Web => {
-            let (tx, rx) = unbounded::<Option<Segment>>().try_into().unwrap();
-            let rt = get_runtime();
+        s.spawn(move || {
+            // clips finish out of order, keep them until the ones before them are saved
+            let mut pending = BTreeMap::new();
+            let mut next_seq = 0u64;
+            for (clip, segments) in done_rx {
+                pending.insert(clip.seq, (clip, segments));
+                while let Some((clip, segments)) = pending.remove(&next_seq) {
+                    let segments = transcriber.label_speakers(saver_state.as_mut(), &clip, segments);
+                    transcriber.save(&clip, segments);
+                    next_seq += 1;
+                }
+            }
+        });
 
-            let pool2 = pool.clone().expect("database pool is required when source is web for pulling data for web");
+        let mut seq = 0u64;
+        let mut stopped = false;
+        let result = run(&mut |timestamp_millis, buf: &Vec<i16>, vad_probability| {
+            if stopped {
+                return;
+            }
+            if clip_tx.send(transcriber.clip(seq, timestamp_millis, buf, vad_probability)).is_err() {
+                error!("transcribe workers have stopped, dropping speech from now on");
+                stopped = true;
+            }
+            seq += 1;
+        });
+        // the workers, then the saving thread, finish what is queued and stop
+        drop(clip_tx);
+        match (result, stopped) {
+            (Err(e), _) => Err(e),
+            (Ok(()), true) => Err("transcribe workers have stopped".into()),
+            (Ok(()), false) => Ok(()),
+        }
+    })
+}
 
-            process_with_vad(&rx,
-                || {
-                    let pool2 = pool2.clone();
-                    rt.block_on(async {
-                        TranscribeWebServer::new(5002,tx.clone(),pool2).start_webserver().await
-                    });
-                },
-                closure_annotated)?;
+// lets the shows api tell whether the ingest is still running, until control is stopped
+fn spawn_show_heartbeat(pool: Pool<Postgres>, show_name: String, control: Option<Arc<IngestControl>>) {
+    thread::spawn(move || {
+        loop {
+            if let Some(control) = &control {
+                if control.is_stopped() {
+                    break;
+                }
+            }
+            let paused = control.as_ref().map(|control| control.is_paused()).unwrap_or(false);
+            if !paused {
+                if let Err(e) = get_runtime().block_on(show_heartbeat(&pool, show_name.as_str())) {
+                    warn!("failed to update show heartbeat: {}", e);
+                }
+            }
+            thread::sleep(std::time::Duration::from_secs(SHOW_HEARTBEAT_SECONDS));
+        }
+    });
+}
+
+/// Transcribe a url until it ends or `control` is stopped, used by the ingest control api.
+pub fn ingest_url(ctx: &WhisperContext, model: &str, request: &IngestRequest, n_threads: usize, decoding: &DecodingConfig, output: TranscriptOutput, control: Arc<IngestControl>) -> Result<(), Box<dyn std::error::Error>> {
+    let rt = get_runtime();
 
+    if let Some(pool) = &output.pool {
+        rt.block_on(register_show(pool, request.show_name.as_str(), request.language.as_str(), model))?;
+        spawn_show_heartbeat(pool.clone(), request.show_name.clone(), Some(control.clone()));
+    }
+
+    let metrics = ShowMetrics::for_show(request.show_name.as_str(), true);
+    let params = build_params(request.language.as_str(), n_threads, decoding);
+    let mut transcriber = SpeechTranscriber::new(ctx, request.show_name.as_str(), request.language.as_str(), params, metrics.clone(), output, request.prompt.as_ref());
+    transcriber.n_threads = n_threads;
+    transcriber.translate = request.translate;
+    transcriber.detector = LanguageDetector::from_config(request.language.as_str(), request.allowed_languages.as_deref(), n_threads)?;
+    let mut state = ctx.create_state()?;
+
+    let (tx, rx) = bounded::<Option<Segment>>((TARGET_SAMPLE_RATE*60).try_into().unwrap());
+    let mut result = Ok(());
+    process_with_vad(&rx, Some(&*metrics),
+        || {
+            // let vad finish what it has
+            result = end_stream_on_error(&tx, streaming_url(request.url.as_str(),TARGET_SAMPLE_RATE,SAMPLE_SIZE,&tx,Some(&*metrics),Some(&*control)));
         },
+        |timestamp_millis, buf: &Vec<i16>, vad_probability| transcriber.transcribe_speech(&mut state, timestamp_millis, buf, vad_probability))?;
+
+    Ok(result?)
+}
+
+// also saves to db if database_name is provided in config
+// serve_addr also serves the web page and read apis while transcribing
+// metrics_addr serves only /metrics and /healthz
+// num_workers speech clips are transcribed at once, each with num_transcribe_threads
+pub fn transcribe_url(config: Config,num_transcribe_threads: Option<usize>,num_workers: usize,model_download_url: &str,serve_addr: Option<SocketAddr>,metrics_addr: Option<SocketAddr>) -> Result<(), Box<dyn std::error::Error>> {
+
+    let rt = get_runtime();
+    //eprintln!("transcribe_url");
+
+    let source = &config.source;
+    let mut pool: Option<Pool<_>> = None;
+
+    if let Some(database_config) = &config.database_config {
+        pool = Some(init_db_from_config(rt,database_config)?);
+    }
+
+    if let Some(pool) = &pool {
+        let model = get_filename_from_url(model_download_url)?;
+        rt.block_on(register_show(pool, config.show_name.as_str(), config.language.as_str(), model.as_str()))?;
+        spawn_show_heartbeat(pool.clone(), config.show_name.clone(), None);
+    }
+
+
+    let ctx = load_whisper_context(model_download_url).expect("failed to load model");
+
+    let n_threads = num_transcribe_threads.unwrap_or_else(default_num_transcribe_threads);
+
+    //let mut file = File::create("transcript.jsonl").expect("failed to create file");
+
+    // audio from the web page comes and goes, so it is not checked by /healthz
+    let metrics = ShowMetrics::for_show(config.show_name.as_str(), !matches!(source, crate::config::Source::Web));
+
+    // the context is made of the clip before, which other workers are still transcribing
+    let mut prompt = config.prompt.clone();
+    if let Some(prompt) = prompt.as_mut().filter(|prompt| num_workers > 1 && prompt.context.is_some()) {
+        warn!("[prompt.context] is turned off with more than one worker");
+        prompt.context = None;
+    }
+
+    let params = build_params(config.language.as_str(), n_threads, &config.decoding);
+    let mut transcriber = SpeechTranscriber::new(&ctx, config.show_name.as_str(), config.language.as_str(), params, metrics.clone(), TranscriptOutput {
+        pool: pool.clone(),
+        audio_archive_dir: config.audio_archive_dir.as_ref(),
+        filter_config: config.filter.as_ref(),
+        postprocess: Some(&config.postprocess),
+        speakers: config.diarization.as_ref().map(SpeakerEmbedder::new).transpose()?.map(Arc::new),
+    }, prompt.as_ref());
+    transcriber.n_threads = n_threads;
+    transcriber.translate = config.translate;
+    transcriber.detector = LanguageDetector::from_config(config.language.as_str(), config.allowed_languages.as_deref(), n_threads)?;
+
+    // source = web already runs the web server below
+    if let (Some(serve_addr), false) = (serve_addr, matches!(source, crate::config::Source::Web)) {
+        let pool2 = pool.clone().ok_or("database config is required to serve transcripts")?;
+        let audio_archive_dir = config.audio_archive_dir.clone();
+        let listener = bind_listener(serve_addr)?;
+        thread::spawn(move || {
+            get_runtime().block_on(async {
+                TranscribeWebServer::new(serve_addr, None, pool2).with_listener(listener).with_audio_archive(audio_archive_dir).start_webserver().await
+            });
+        });
+    }
+
+    if let Some(metrics_addr) = metrics_addr {
+        let listener = bind_listener(metrics_addr)?;
+        thread::spawn(move || {
+            get_runtime().block_on(async {
+                start_metrics_server(listener).await
+            });
+        });
     }
 
+    transcribe_with_workers(&transcriber, num_workers, |on_speech| {
+        match source {
+            crate::config::Source::Url => {
+                let url = config.url.as_ref().expect("url is required when source is url");
+                let (tx, rx) = bounded::<Option<Segment>>((TARGET_SAMPLE_RATE*60).try_into().unwrap());
+                let mut result = Ok(());
+                process_with_vad(&rx, Some(&*metrics),
+                    || {
+                        result = end_stream_on_error(&tx, streaming_url(url,TARGET_SAMPLE_RATE,SAMPLE_SIZE,&tx,Some(&*metrics),None));
+                    },
+                    on_speech)?;
+                result?;
+            },
+            crate::config::Source::Microphone => {
+                let (tx, rx) = unbounded::<Option<Segment>>().try_into().unwrap();
+                process_with_vad(&rx, Some(&*metrics),
+                    || {
+                        record_from_mic(&tx,SAMPLE_SIZE).unwrap();
+                    },
+                    on_speech)?;
+            },
+            crate::config::Source::Web => {
+                let (tx, rx) = unbounded::<Option<Segment>>().try_into().unwrap();
+                let rt = get_runtime();
+
+                let pool2 = pool.clone().expect("database pool is required when source is web for pulling data for web");
+
+                process_with_vad(&rx, Some(&*metrics),
+                    || {
+                        let pool2 = pool2.clone();
+                        rt.block_on(async {
+                            TranscribeWebServer::new(serve_addr.unwrap_or_else(default_web_addr),Some(tx.clone()),pool2)
+                                .with_audio_archive(config.audio_archive_dir.clone())
+                                .start_webserver().await
+                        });
+                    },
+                    on_speech)?;
+
+            },
+        }
+        Ok(())
+    })?;
+
     Ok(())
 }
\ No newline at end of file
@@ -5,23 +5,34 @@
 //! ```
 
 use axum::{
-  http::{header, HeaderValue, StatusCode, Uri}, response::{Html, IntoResponse, Response}, routing::{get, Router}, Json
+  extract::{DefaultBodyLimit, Multipart, Path}, http::{header, HeaderValue, StatusCode, Uri}, response::{Html, IntoResponse, Response}, routing::{get, Router}, Json
 };
-use chrono::{DateTime, FixedOffset, Utc};
+use chrono::{DateTime, FixedOffset};
 use crossbeam::channel::Sender;
 use rust_embed::Embed;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{Pool, Postgres, Row};
-use std::net::SocketAddr;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
 
 use tower_http::trace::TraceLayer;
 
-use crate::{streaming::Segment, vad_processor::SAMPLE_SIZE};
+use crate::{ingest::{IngestInfo, IngestManager, IngestRequest}, language::AUTO_LANGUAGE, postprocess::is_cjk, shows::{list_shows, ShowInfo}, corrections::{correct_transcript, export_corrections, get_revision_history, CorrectedTranscript, RevisionHistory, TranscriptRevision}, audio_archive::{wav_to_opus, AudioArchive}, audio_decoder::{CompressedAudioDecoder, CompressedFormat, PcmFormat, PcmResampler, SampleEncoding}, config::Config, metrics::{check_health, gather_metrics}, jobs::{JobInfo, JobManager}, runtime_utils::get_runtime, subtitle::{to_srt, to_text, to_vtt, with_translation}, db::{get_transcript_audio, init_db_from_config, parse_cursor, parse_time_param, query_transcripts, restore_offset_plus, SortOrder, TimeRangeQuery}, search::{parse_timestamp, search_transcripts, SearchParams, MAX_SEARCH_LIMIT}, streaming::Segment, vad_processor::SAMPLE_SIZE};
 
 
 use futures::StreamExt;
 
+pub const DEFAULT_WEB_PORT: u16 = 5002;
+
+// uploaded recordings can be long
+const MAX_UPLOAD_BYTES: usize = 1024 * 1024 * 1024;
+
+pub fn default_web_addr() -> SocketAddr {
+  SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_WEB_PORT))
+}
+
 // We use static route matchers ("/" and "/index.html") to serve our home
 // page.
 async fn index_handler() -> impl IntoResponse {
@@ -79,13 +90,56 @@ async fn test_api(axum::extract::State(state): axum::extract::State<AppState>) -
 
 
 #[axum::debug_handler]
-async fn set_session_id(axum::extract::State(state): axum::extract::State<AppState>,input: Json<SessionIdInput>) -> Json<TestResponse> {
+async fn set_session_id(axum::extract::State(state): axum::extract::State<AppState>,input: Json<SessionIdInput>) -> Result<Json<TestResponse>, (StatusCode, Json<TestResponse>)> {
   //eprintln!("session id: {}", input.id);
+  let pcm_format = match session_pcm_format(&input) {
+    Ok(pcm_format) => pcm_format,
+    Err(e) => {
+      return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+        message: format!("invalid audio format: {}", e),
+      })));
+    }
+  };
   let mut session_id = state.session_id.lock().await;
   *session_id = Some(input.id.clone());
-  Json(TestResponse {
+  *state.session_format.lock().await = pcm_format;
+  // closing the previous session's decoder flushes its remaining audio
+  *state.decoder.lock().await = None;
+  let previous_resampler = state.resampler.lock().await.take();
+  if let Some(tx) = &state.tx {
+    if let Err(e) = finish_resampler(previous_resampler, tx) {
+      eprintln!("Error flushing the resampler of the previous session: {}", e);
+    }
+  }
+  Ok(Json(TestResponse {
     message: "success".to_string(),
-  })
+  }))
+}
+
+fn session_pcm_format(input: &SessionIdInput) -> Result<PcmFormat, Box<dyn std::error::Error>> {
+  let default = PcmFormat::default();
+  let encoding = match &input.sample_encoding {
+    Some(encoding) => SampleEncoding::parse(encoding)?,
+    None => default.encoding,
+  };
+  PcmFormat::new(input.sample_rate.unwrap_or(default.sample_rate), input.channels.unwrap_or(default.channels), encoding)
+}
+
+// X-Sample-Rate, X-Channels and X-Sample-Encoding override the format declared with the session
+fn request_pcm_format(headers: &axum::http::HeaderMap, session_format: PcmFormat) -> Result<PcmFormat, Box<dyn std::error::Error>> {
+  let sample_rate = match headers.get("X-Sample-Rate") {
+    Some(value) => value.to_str()?.trim().parse::<u32>()?,
+    None => session_format.sample_rate,
+  };
+  let channels = match headers.get("X-Channels") {
+    Some(value) => value.to_str()?.trim().parse::<u16>()?,
+    None => session_format.channels,
+  };
+  let encoding = match headers.get("X-Sample-Encoding") {
+    Some(value) => SampleEncoding::parse(value.to_str()?)?,
+    None => session_format.encoding,
+  };
+  PcmFormat::new(sample_rate, channels, encoding)
 }
 
 fn process_chunk(buffer: &[u8],tx: &Sender::<Option<Segment>>,timestamp_millis: i64) {
@@ -143,6 +197,95 @@ async fn read_stream(mut body_stream: axum::body::BodyDataStream,tx: &Sender::<O
   Ok(())
 }
 
+fn send_samples(samples: &[i16], tx: &Sender::<Option<Segment>>, timestamp_millis: i64) -> Result<(), Box<dyn std::error::Error>> {
+  for chunk in samples.chunks(SAMPLE_SIZE) {
+    tx.send(Some(Segment {
+      samples: chunk.to_vec(),
+      timestamp_millis,
+    }))?;
+  }
+  Ok(())
+}
+
+// sends what the resampler of a finished session still holds
+fn finish_resampler(session: Option<(String, PcmResampler, i64)>, tx: &Sender::<Option<Segment>>) -> Result<(), Box<dyn std::error::Error>> {
+  if let Some((_, resampler, timestamp_millis)) = session {
+    send_samples(&resampler.finish()?, tx, timestamp_millis)?;
+  }
+  Ok(())
+}
+
+// pcm in any other format than 16 kHz mono s16le, resampled as one stream per session
+async fn read_converted_stream(state: &AppState, session_id: &str, mut body_stream: axum::body::BodyDataStream,tx: &Sender::<Option<Segment>>, timestamp_millis: i64, pcm_format: PcmFormat) -> Result<(), Box<dyn std::error::Error>> {
+  
+  let mut resampler = state.resampler.lock().await;
+  
+  let reuse = matches!(&*resampler, Some((id, r, _)) if id == session_id && r.format == pcm_format);
+  if !reuse {
+    // a format change ends the previous stream
+    finish_resampler(resampler.take(), tx)?;
+    *resampler = Some((session_id.to_string(), PcmResampler::new(pcm_format)?, timestamp_millis));
+  }
+  
+  let (_, current, last_timestamp_millis) = resampler.as_mut().unwrap();
+  *last_timestamp_millis = timestamp_millis;
+  
+  let mut samples = Vec::new();
+  while let Some(chunk) = body_stream.next().await {
+    samples.extend(current.process(&chunk?)?);
+    if samples.len() >= SAMPLE_SIZE {
+      let whole_chunks = samples.len() - samples.len() % SAMPLE_SIZE;
+      send_samples(&samples[..whole_chunks], tx, timestamp_millis)?;
+      samples.drain(..whole_chunks);
+    }
+  }
+  send_samples(&samples, tx, timestamp_millis)?;
+  
+  Ok(())
+}
+
+async fn read_compressed_stream(state: &AppState, session_id: &str, format: CompressedFormat, mut body_stream: axum::body::BodyDataStream, tx: &Sender::<Option<Segment>>, timestamp_millis: i64) -> Result<(), Box<dyn std::error::Error>> {
+  
+  let mut decoder = state.decoder.lock().await;
+  
+  let reuse = matches!(&*decoder, Some((id, d)) if id == session_id && d.format == format);
+  if !reuse {
+    let tx = tx.clone();
+    let new_decoder = CompressedAudioDecoder::spawn(format, move |pcm, timestamp_millis| {
+      process_chunk(pcm, &tx, timestamp_millis);
+    })?;
+    *decoder = Some((session_id.to_string(), new_decoder));
+  }
+  
+  let (_, current) = decoder.as_mut().unwrap();
+  while let Some(chunk) = body_stream.next().await {
+    let data = chunk?;
+    if let Err(e) = current.write(&data, timestamp_millis).await {
+      // the container header is gone with the old decoder, the client has to start over
+      *decoder = None;
+      return Err(format!("decoder stopped, set a new session id to restart: {}", e).into());
+    }
+  }
+  
+  Ok(())
+}
+
+// None for raw 16 kHz s16le pcm, which is also assumed when there is no content type
+fn parse_audio_content_type(content_type: Option<&HeaderValue>) -> Result<Option<CompressedFormat>, Box<dyn std::error::Error>> {
+  let content_type = match content_type {
+    Some(content_type) => content_type.to_str()?,
+    None => return Ok(None),
+  };
+  let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+  match mime.as_str() {
+    "" | "application/octet-stream" | "audio/pcm" | "audio/l16" => Ok(None),
+    _ => match CompressedFormat::from_mime(&mime) {
+      Some(format) => Ok(Some(format)),
+      None => Err(format!("unsupported content type {}", content_type).into()),
+    },
+  }
+}
+
 fn parse_timestamp_millis(ts: &HeaderValue) -> Result<i64, Box<dyn std::error::Error>> {
   Ok(ts.to_str()?.parse::<i64>()?)
 }
@@ -154,6 +297,15 @@ fn parse_session_id(session_id_header: &HeaderValue) -> Result<&str, Box<dyn std
 #[axum::debug_handler]
 async fn audio_input(axum::extract::State(state): axum::extract::State<AppState>,request: axum::http::Request<axum::body::Body>) -> Result<Json<TestResponse>, impl IntoResponse> {
   
+  let tx = match &state.tx {
+    Some(tx) => tx,
+    None => {
+      return Err((StatusCode::NOT_FOUND,Json(TestResponse {
+        message: "audio input is only available when transcribing with source = web".to_string(),
+      })));
+    }
+  };
+  
   let timestamp_millis = if let Some(ts)= request.headers().get("X-Recording-Timestamp") {
     match parse_timestamp_millis(ts) {
       Ok(ts) => ts,
@@ -204,9 +356,40 @@ async fn audio_input(axum::extract::State(state): axum::extract::State<AppState>
     })));
   }
   
+  let compressed_format = match parse_audio_content_type(request.headers().get(header::CONTENT_TYPE)) {
+    Ok(format) => format,
+    Err(e) => {
+      return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE,Json(TestResponse {
+        message: e.to_string(),
+      })));
+    }
+  };
+  
+  let pcm_format = match compressed_format {
+    // ffmpeg works out the format of compressed audio by itself
+    Some(_) => PcmFormat::default(),
+    None => {
+      let session_format = *state.session_format.lock().await;
+      match request_pcm_format(request.headers(), session_format) {
+        Ok(pcm_format) => pcm_format,
+        Err(e) => {
+          return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+            message: format!("invalid audio format: {}", e),
+          })));
+        }
+      }
+    }
+  };
+  
   let body_stream = request.into_body().into_data_stream();
   
-  match read_stream(body_stream, &state.tx, timestamp_millis).await {
+  let result = match compressed_format {
+    Some(format) => read_compressed_stream(&state, &current_session_id, format, body_stream, tx, timestamp_millis).await,
+    None if pcm_format.is_native() => read_stream(body_stream, tx, timestamp_millis).await,
+    None => read_converted_stream(&state, &current_session_id, body_stream, tx, timestamp_millis, pcm_format).await,
+  };
+  
+  match result {
     Ok(_) => {},
     Err(e) => {
       return Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
@@ -245,7 +428,7 @@ async fn get_transcripts(state: axum::extract::State<AppState>,q: axum::extract:
     
     
     let pool = &state.pool;
-    query = sqlx::query(r#"SELECT id,"timestamp",content FROM transcripts where show_name = $1 and id >= $2 and id < $3 order by id limit 1000"#)
+    query = sqlx::query(r#"SELECT id,"timestamp",coalesce(corrected_content, content) AS content FROM transcripts where show_name = $1 and id >= $2 and id < $3 order by id limit 1000"#)
     .bind(q.show_name.clone())
     .bind(after_id_2)
     .bind(before_id)
@@ -265,7 +448,7 @@ async fn get_transcripts(state: axum::extract::State<AppState>,q: axum::extract:
     }
 
     let pool = &state.pool;
-    query = sqlx::query(r#"SELECT id,"timestamp",content FROM transcripts where show_name = $1 and id > $2 order by id limit 1000"#)
+    query = sqlx::query(r#"SELECT id,"timestamp",coalesce(corrected_content, content) AS content FROM transcripts where show_name = $1 and id > $2 order by id limit 1000"#)
     .bind(q.show_name.clone())
     .bind(after_id)
     .fetch(pool);
@@ -280,7 +463,7 @@ async fn get_transcripts(state: axum::extract::State<AppState>,q: axum::extract:
     let test =timestamp.and_utc().to_rfc3339();
     json!({"id": id, "timestamp": test, "content": content}).to_string()
   })
-  .map(|json_row| Ok::<String, std::convert::Infallible>(format!("{}\n",json_row).into()));
+  .map(|json_row| Ok::<String, std::convert::Infallible>(format!("{}\n",json_row)));
   
   // Create the Axum body from the stream
   let body = axum::body::Body::from_stream(rows_stream);
@@ -288,9 +471,68 @@ async fn get_transcripts(state: axum::extract::State<AppState>,q: axum::extract:
   (StatusCode::OK,body).into_response()
 }
 
+#[derive(Deserialize)]
+struct TimeRangeParams {
+  show_name: String,
+  start: Option<String>,
+  end: Option<String>,
+  // fixed offset like +08:00, used for naive start/end and for the returned timestamps
+  tz: Option<String>,
+  cursor: Option<String>,
+  limit: Option<i64>,
+  order: Option<String>,
+  // include word timestamps
+  words: Option<bool>,
+  // leave out rows flagged low_confidence
+  hide_low_confidence: Option<bool>,
+}
+
+fn parse_time_range_params(q: &TimeRangeParams) -> Result<(TimeRangeQuery, FixedOffset), Box<dyn std::error::Error>> {
+  let tz = match &q.tz {
+    Some(tz) => restore_offset_plus(tz).parse::<FixedOffset>().map_err(|e| format!("invalid tz {}: {}", tz, e))?,
+    None => FixedOffset::east_opt(0).unwrap(),
+  };
+  let order = match q.order.as_deref() {
+    None | Some("asc") => SortOrder::Asc,
+    Some("desc") => SortOrder::Desc,
+    Some(order) => return Err(format!("invalid order {}, expected asc or desc", order).into()),
+  };
+  let query = TimeRangeQuery {
+    show_name: q.show_name.clone(),
+    start: q.start.as_deref().map(|ts| parse_time_param(ts, &tz)).transpose()?,
+    end: q.end.as_deref().map(|ts| parse_time_param(ts, &tz)).transpose()?,
+    cursor: q.cursor.as_deref().map(parse_cursor).transpose()?,
+    limit: q.limit.unwrap_or(100).clamp(1, 1000),
+    order,
+    include_words: q.words.unwrap_or(false),
+    hide_low_confidence: q.hide_low_confidence.unwrap_or(false),
+  };
+  Ok((query, tz))
+}
+
+#[axum::debug_handler]
+async fn get_transcripts_by_time(state: axum::extract::State<AppState>,q: axum::extract::Query<TimeRangeParams>) -> Result<Json<crate::db::TranscriptPage>, (StatusCode, Json<TestResponse>)> {
+  let (query, tz) = match parse_time_range_params(&q) {
+    Ok(parsed) => parsed,
+    Err(e) => {
+      return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+        message: format!("invalid parameters: {}", e),
+      })));
+    }
+  };
+
+  match query_transcripts(&state.pool, &query, &tz).await {
+    Ok(page) => Ok(Json(page)),
+    Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+      message: format!("error querying transcripts: {}", e),
+    }))),
+  }
+}
+
 async fn get_show_names(state: axum::extract::State<AppState>) -> impl IntoResponse {
   let pool = &state.pool;
-  let mut rows = sqlx::query(r#"SELECT distinct show_name FROM transcripts"#)
+  // a show registered when transcription starts has no transcripts until the first segment is saved
+  let mut rows = sqlx::query(r#"SELECT show_name FROM shows WHERE row_count > 0 ORDER BY show_name"#)
   .fetch(pool);
   let mut show_names = Vec::new();
   while let Some(row) = rows.next().await {
@@ -301,6 +543,557 @@ async fn get_show_names(state: axum::extract::State<AppState>) -> impl IntoRespo
   Json(show_names)
 }
 
+#[axum::debug_handler]
+async fn get_shows(state: axum::extract::State<AppState>) -> Result<Json<Vec<ShowInfo>>, (StatusCode, Json<TestResponse>)> {
+  match list_shows(&state.pool).await {
+    Ok(shows) => Ok(Json(shows)),
+    Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+      message: format!("error loading shows: {}", e),
+    }))),
+  }
+}
+
+#[derive(Deserialize)]
+struct AudioClipQuery {
+  // seconds of audio to add before and after the transcript
+  padding: Option<f64>,
+  // wav (default) or opus
+  format: Option<String>,
+}
+
+// at most this much padding on each side
+const MAX_CLIP_PADDING_SECONDS: f64 = 30.0;
+
+#[derive(PartialEq, Debug)]
+enum ByteRange {
+  // no range, or one that is malformed or not supported, which is ignored
+  Full,
+  Partial(usize, usize),
+  // well formed but starting past the end
+  Unsatisfiable,
+}
+
+// a single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range
+fn parse_byte_range(range: &str, len: usize) -> ByteRange {
+  let range = match range.trim().strip_prefix("bytes=") {
+    Some(range) if !range.contains(',') => range,
+    _ => return ByteRange::Full,
+  };
+  let (start, end) = match range.split_once('-') {
+    Some((start, end)) => (start.trim(), end.trim()),
+    None => return ByteRange::Full,
+  };
+  let (start, end) = match (start, end) {
+    ("", suffix) => match suffix.parse::<usize>() {
+      Ok(suffix) if suffix > 0 && len > 0 => (len - suffix.min(len), len - 1),
+      _ => return ByteRange::Full,
+    },
+    (start, "") => match start.parse::<usize>() {
+      Ok(start) => (start, usize::MAX),
+      Err(_) => return ByteRange::Full,
+    },
+    (start, end) => match (start.parse::<usize>(), end.parse::<usize>()) {
+      (Ok(start), Ok(end)) if start <= end => (start, end),
+      _ => return ByteRange::Full,
+    },
+  };
+  if start >= len {
+    return ByteRange::Unsatisfiable;
+  }
+  ByteRange::Partial(start, end.min(len - 1))
+}
+
+fn ranged_response(headers: &axum::http::HeaderMap, body: Vec<u8>, content_type: &'static str) -> Response {
+  let len = body.len();
+  let range = headers.get(header::RANGE).and_then(|range| range.to_str().ok());
+  match range.map(|range| parse_byte_range(range, len)).unwrap_or(ByteRange::Full) {
+    ByteRange::Full => ([(header::CONTENT_TYPE, content_type), (header::ACCEPT_RANGES, "bytes")], body).into_response(),
+    ByteRange::Partial(start, end) => (
+      StatusCode::PARTIAL_CONTENT,
+      [
+        (header::CONTENT_TYPE, content_type.to_string()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len)),
+      ],
+      body[start..=end].to_vec(),
+    ).into_response(),
+    ByteRange::Unsatisfiable => (
+      StatusCode::RANGE_NOT_SATISFIABLE,
+      [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+    ).into_response(),
+  }
+}
+
+// cjk is written without spaces, so only other scripts get one between segments
+fn join_segment_texts<'a>(texts: impl Iterator<Item = &'a str>) -> String {
+  let joins_cjk = |c: Option<char>| c.map(|c| is_cjk(c) || (!c.is_ascii() && !c.is_alphanumeric())).unwrap_or(false);
+  let mut joined = String::new();
+  for text in texts.filter(|text| !text.is_empty()) {
+    if !joined.is_empty() && !(joins_cjk(joined.chars().last()) && joins_cjk(text.chars().next())) {
+      joined.push(' ');
+    }
+    joined.push_str(text);
+  }
+  joined
+}
+
+#[axum::debug_handler]
+async fn get_transcript_audio_clip(state: axum::extract::State<AppState>,Path(id): Path<i64>,q: axum::extract::Query<AudioClipQuery>,headers: axum::http::HeaderMap) -> Result<Response, (StatusCode, Json<TestResponse>)> {
+  let audio_archive = match &state.audio_archive {
+    Some(audio_archive) => audio_archive.clone(),
+    None => {
+      return Err((StatusCode::NOT_FOUND,Json(TestResponse {
+        message: "audio archive is not enabled, set audio_archive_dir in the config".to_string(),
+      })));
+    }
+  };
+  
+  let padding = q.padding.unwrap_or(0.0);
+  if !(0.0..=MAX_CLIP_PADDING_SECONDS).contains(&padding) {
+    return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+      message: format!("padding must be between 0 and {} seconds", MAX_CLIP_PADDING_SECONDS),
+    })));
+  }
+  
+  let format = q.format.as_deref().unwrap_or("wav");
+  if format != "wav" && format != "opus" {
+    return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+      message: format!("unknown format {}, expected wav or opus", format),
+    })));
+  }
+  
+  let audio = match get_transcript_audio(&state.pool, id).await {
+    Ok(Some(audio)) => audio,
+    Ok(None) => {
+      return Err((StatusCode::NOT_FOUND,Json(TestResponse {
+        message: format!("no audio for transcript {}", id),
+      })));
+    },
+    Err(e) => {
+      return Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+        message: format!("error loading transcript: {}", e),
+      })));
+    }
+  };
+  
+  let wav = match tokio::task::spawn_blocking(move || {
+    audio_archive.read_clip(&audio, (padding * 1000.0) as i64).map_err(|e| e.to_string())
+  }).await.unwrap() {
+    Ok(wav) => wav,
+    Err(e) => {
+      return Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+        message: format!("error reading audio: {}", e),
+      })));
+    }
+  };
+  
+  let response = match format {
+    "opus" => match wav_to_opus(wav).await {
+      Ok(opus) => ranged_response(&headers, opus, "audio/ogg"),
+      Err(e) => {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+          message: format!("error encoding opus: {}", e),
+        })));
+      }
+    },
+    _ => ranged_response(&headers, wav, "audio/wav"),
+  };
+  Ok(response)
+}
+
+#[derive(Deserialize)]
+struct CorrectionInput {
+  content: String,
+  // name of whoever made the correction
+  editor: String,
+}
+
+#[axum::debug_handler]
+async fn correct_transcript_handler(state: axum::extract::State<AppState>,Path(id): Path<i64>,input: Json<CorrectionInput>) -> Result<Json<TranscriptRevision>, (StatusCode, Json<TestResponse>)> {
+  if input.editor.trim().is_empty() {
+    return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+      message: "editor is required".to_string(),
+    })));
+  }
+  match correct_transcript(&state.pool, id, input.content.trim(), input.editor.trim()).await {
+    Ok(Some(revision)) => Ok(Json(revision)),
+    Ok(None) => Err((StatusCode::NOT_FOUND,Json(TestResponse {
+      message: format!("transcript {} not found", id),
+    }))),
+    Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+      message: format!("error saving correction: {}", e),
+    }))),
+  }
+}
+
+#[axum::debug_handler]
+async fn get_revisions(state: axum::extract::State<AppState>,Path(id): Path<i64>) -> Result<Json<RevisionHistory>, (StatusCode, Json<TestResponse>)> {
+  match get_revision_history(&state.pool, id).await {
+    Ok(Some(history)) => Ok(Json(history)),
+    Ok(None) => Err((StatusCode::NOT_FOUND,Json(TestResponse {
+      message: format!("transcript {} not found", id),
+    }))),
+    Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+      message: format!("error loading revisions: {}", e),
+    }))),
+  }
+}
+
+#[derive(Deserialize)]
+struct CorrectionsQuery {
+  show_name: Option<String>,
+  from: Option<String>,
+  to: Option<String>,
+}
+
+#[axum::debug_handler]
+async fn get_corrections(state: axum::extract::State<AppState>,q: axum::extract::Query<CorrectionsQuery>) -> Result<Json<Vec<CorrectedTranscript>>, (StatusCode, Json<TestResponse>)> {
+  let (from, to) = match (parse_optional_timestamp(&q.from), parse_optional_timestamp(&q.to)) {
+    (Ok(from), Ok(to)) => (from, to),
+    (Err(e), _) | (_, Err(e)) => {
+      return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+        message: format!("error parsing timestamp: {}", e),
+      })));
+    }
+  };
+  match export_corrections(&state.pool, q.show_name.as_deref(), from, to).await {
+    Ok(corrections) => Ok(Json(corrections)),
+    Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+      message: format!("error exporting corrections: {}", e),
+    }))),
+  }
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+  q: String,
+  show_name: Option<String>,
+  from: Option<String>,
+  to: Option<String>,
+  limit: Option<i64>,
+}
+
+fn parse_optional_timestamp(ts: &Option<String>) -> Result<Option<DateTime<FixedOffset>>, Box<dyn std::error::Error>> {
+  match ts {
+    Some(ts) => Ok(Some(parse_timestamp(&restore_offset_plus(ts))?)),
+    None => Ok(None),
+  }
+}
+
+#[axum::debug_handler]
+async fn search(state: axum::extract::State<AppState>,q: axum::extract::Query<SearchQuery>) -> Result<Json<Vec<crate::search::SearchResult>>, (StatusCode, Json<TestResponse>)> {
+  let (from, to) = match (parse_optional_timestamp(&q.from), parse_optional_timestamp(&q.to)) {
+    (Ok(from), Ok(to)) => (from, to),
+    (Err(e), _) | (_, Err(e)) => {
+      return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+        message: format!("error parsing timestamp, expected rfc3339: {}", e),
+      })));
+    }
+  };
+
+  let params = SearchParams {
+    query: q.q.clone(),
+    show_name: q.show_name.clone(),
+    from,
+    to,
+    limit: q.limit.unwrap_or(50).clamp(1, MAX_SEARCH_LIMIT),
+  };
+
+  match search_transcripts(&state.pool, &params).await {
+    Ok(results) => Ok(Json(results)),
+    Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+      message: format!("error searching transcripts: {}", e),
+    }))),
+  }
+}
+
+fn require_jobs(state: &AppState) -> Result<Arc<JobManager>, (StatusCode, Json<TestResponse>)> {
+  match &state.jobs {
+    Some(jobs) => Ok(jobs.clone()),
+    None => Err((StatusCode::NOT_FOUND,Json(TestResponse {
+      message: "transcription jobs are not enabled, start serve with --jobs".to_string(),
+    }))),
+  }
+}
+
+fn require_ingests(state: &AppState) -> Result<Arc<IngestManager>, (StatusCode, Json<TestResponse>)> {
+  match &state.ingests {
+    Some(ingests) => Ok(ingests.clone()),
+    None => Err((StatusCode::NOT_FOUND,Json(TestResponse {
+      message: "ingest control is not enabled, start serve with --ingest".to_string(),
+    }))),
+  }
+}
+
+fn ingest_not_found(show_name: &str) -> (StatusCode, Json<TestResponse>) {
+  (StatusCode::NOT_FOUND,Json(TestResponse {
+    message: format!("no ingest for show {}", show_name),
+  }))
+}
+
+#[axum::debug_handler]
+async fn list_ingests(state: axum::extract::State<AppState>) -> Result<Json<Vec<IngestInfo>>, (StatusCode, Json<TestResponse>)> {
+  let ingests = require_ingests(&state)?;
+  Ok(Json(ingests.list()))
+}
+
+#[axum::debug_handler]
+async fn start_ingest(state: axum::extract::State<AppState>,input: Json<IngestRequest>) -> Result<(StatusCode, Json<IngestInfo>), (StatusCode, Json<TestResponse>)> {
+  let ingests = require_ingests(&state)?;
+  match ingests.start(input.0) {
+    Ok(info) => Ok((StatusCode::CREATED, Json(info))),
+    Err(e) => Err((StatusCode::CONFLICT,Json(TestResponse {
+      message: e,
+    }))),
+  }
+}
+
+#[axum::debug_handler]
+async fn get_ingest(state: axum::extract::State<AppState>,Path(show_name): Path<String>) -> Result<Json<IngestInfo>, (StatusCode, Json<TestResponse>)> {
+  let ingests = require_ingests(&state)?;
+  ingests.get(&show_name).map(Json).ok_or_else(|| ingest_not_found(&show_name))
+}
+
+#[axum::debug_handler]
+async fn pause_ingest(state: axum::extract::State<AppState>,Path(show_name): Path<String>) -> Result<Json<IngestInfo>, (StatusCode, Json<TestResponse>)> {
+  let ingests = require_ingests(&state)?;
+  ingests.pause(&show_name).map(Json).ok_or_else(|| ingest_not_found(&show_name))
+}
+
+#[axum::debug_handler]
+async fn resume_ingest(state: axum::extract::State<AppState>,Path(show_name): Path<String>) -> Result<Json<IngestInfo>, (StatusCode, Json<TestResponse>)> {
+  let ingests = require_ingests(&state)?;
+  ingests.resume(&show_name).map(Json).ok_or_else(|| ingest_not_found(&show_name))
+}
+
+#[axum::debug_handler]
+async fn stop_ingest(state: axum::extract::State<AppState>,Path(show_name): Path<String>) -> Result<Json<IngestInfo>, (StatusCode, Json<TestResponse>)> {
+  let ingests = require_ingests(&state)?;
+  ingests.stop(&show_name).map(Json).ok_or_else(|| ingest_not_found(&show_name))
+}
+
+fn multipart_error(e: axum::extract::multipart::MultipartError) -> (StatusCode, Json<TestResponse>) {
+  (StatusCode::BAD_REQUEST,Json(TestResponse {
+    message: format!("error reading upload: {}", e),
+  }))
+}
+
+fn internal_error(e: std::io::Error) -> (StatusCode, Json<TestResponse>) {
+  (StatusCode::INTERNAL_SERVER_ERROR,Json(TestResponse {
+    message: format!("error saving upload: {}", e),
+  }))
+}
+
+// multipart form with a `file` field and optional `language` and `translate` (true/false) fields
+#[axum::debug_handler]
+async fn create_job(state: axum::extract::State<AppState>,mut multipart: Multipart) -> Result<(StatusCode, Json<JobInfo>), (StatusCode, Json<TestResponse>)> {
+  let jobs = require_jobs(&state)?;
+
+  let mut upload: Option<(String, tempfile::TempPath)> = None;
+  let mut language = state.language.clone();
+  let mut translate = false;
+
+  while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+    match field.name() {
+      Some("file") => {
+        let file_name = field.file_name().unwrap_or("upload").to_string();
+        let mut temp_file = tempfile::NamedTempFile::new().map_err(internal_error)?;
+        while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+          temp_file.write_all(&chunk).map_err(internal_error)?;
+        }
+        upload = Some((file_name, temp_file.into_temp_path()));
+      },
+      Some("language") => {
+        language = field.text().await.map_err(multipart_error)?;
+      },
+      Some("translate") => {
+        translate = field.text().await.map_err(multipart_error)? == "true";
+      },
+      _ => {},
+    }
+  }
+
+  let (file_name, file) = match upload {
+    Some(upload) => upload,
+    None => {
+      return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+        message: "missing file field".to_string(),
+      })));
+    }
+  };
+
+  if language != AUTO_LANGUAGE && whisper_rs::get_lang_id(&language).is_none() {
+    return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+      message: format!("unknown language: {}", language),
+    })));
+  }
+
+  Ok((StatusCode::ACCEPTED, Json(jobs.submit(file_name, language, translate, file))))
+}
+
+fn job_not_found(id: &str) -> (StatusCode, Json<TestResponse>) {
+  (StatusCode::NOT_FOUND,Json(TestResponse {
+    message: format!("job {} not found", id),
+  }))
+}
+
+#[axum::debug_handler]
+async fn get_job(state: axum::extract::State<AppState>,Path(id): Path<String>) -> Result<Json<JobInfo>, (StatusCode, Json<TestResponse>)> {
+  let jobs = require_jobs(&state)?;
+  jobs.get(&id).map(Json).ok_or_else(|| job_not_found(&id))
+}
+
+#[derive(Deserialize)]
+struct JobResultQuery {
+  // json (default), srt or text
+  format: Option<String>,
+  // srt and text of the english translation, for jobs submitted with translate
+  translation: Option<bool>,
+}
+
+#[axum::debug_handler]
+async fn get_job_result(state: axum::extract::State<AppState>,Path(id): Path<String>,q: axum::extract::Query<JobResultQuery>) -> Result<Response, (StatusCode, Json<TestResponse>)> {
+  let jobs = require_jobs(&state)?;
+  let (info, mut segments) = jobs.result(&id).ok_or_else(|| job_not_found(&id))?;
+
+  if info.status != crate::jobs::JobStatus::Completed {
+    return Err((StatusCode::CONFLICT,Json(TestResponse {
+      message: format!("job {} has not completed", id),
+    })));
+  }
+
+  if q.translation.unwrap_or(false) {
+    if !info.translate {
+      return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+        message: format!("job {} was not submitted with translate", id),
+      })));
+    }
+    segments = with_translation(segments);
+  }
+
+  let response = match q.format.as_deref().unwrap_or("json") {
+    "json" => Json(json!({"job": info, "segments": segments})).into_response(),
+    "srt" => ([(header::CONTENT_TYPE, "application/x-subrip; charset=utf-8")], to_srt(&segments)).into_response(),
+    "text" => ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], to_text(&segments)).into_response(),
+    format => {
+      return Err((StatusCode::BAD_REQUEST,Json(TestResponse {
+        message: format!("unknown format {}, expected json, srt or text", format),
+      })));
+    }
+  };
+  Ok(response)
+}
+
+// errors in the shape openai clients expect
+fn openai_error(status: StatusCode, message: String) -> (StatusCode, Json<serde_json::Value>) {
+  (status, Json(json!({
+    "error": {
+      "message": message,
+      "type": "invalid_request_error",
+      "param": null,
+      "code": null,
+    }
+  })))
+}
+
+fn openai_multipart_error(e: axum::extract::multipart::MultipartError) -> (StatusCode, Json<serde_json::Value>) {
+  openai_error(StatusCode::BAD_REQUEST, format!("error reading upload: {}", e))
+}
+
+fn openai_internal_error(e: std::io::Error) -> (StatusCode, Json<serde_json::Value>) {
+  openai_error(StatusCode::INTERNAL_SERVER_ERROR, format!("error saving upload: {}", e))
+}
+
+// compatible with the openai audio transcription api, multipart form with `file`, `model`,
+// and optional `language`, `prompt`, `response_format` and `temperature` fields
+// timestamp_granularities[]=word adds words to verbose_json
+// model, prompt and temperature are accepted but the loaded model and default decoding are used
+#[axum::debug_handler]
+async fn openai_transcriptions(state: axum::extract::State<AppState>,mut multipart: Multipart) -> Result<Response, (StatusCode, Json<serde_json::Value>)> {
+  let jobs = require_jobs(&state).map_err(|(status, e)| openai_error(status, e.0.message))?;
+
+  let mut upload: Option<(String, tempfile::TempPath)> = None;
+  let mut language = state.language.clone();
+  let mut response_format = "json".to_string();
+  let mut word_timestamps = false;
+
+  while let Some(mut field) = multipart.next_field().await.map_err(openai_multipart_error)? {
+    match field.name() {
+      Some("file") => {
+        let file_name = field.file_name().unwrap_or("upload").to_string();
+        let mut temp_file = tempfile::NamedTempFile::new().map_err(openai_internal_error)?;
+        while let Some(chunk) = field.chunk().await.map_err(openai_multipart_error)? {
+          temp_file.write_all(&chunk).map_err(openai_internal_error)?;
+        }
+        upload = Some((file_name, temp_file.into_temp_path()));
+      },
+      Some("language") => {
+        language = field.text().await.map_err(openai_multipart_error)?;
+      },
+      Some("response_format") => {
+        response_format = field.text().await.map_err(openai_multipart_error)?;
+      },
+      Some("timestamp_granularities[]") | Some("timestamp_granularities") => {
+        word_timestamps |= field.text().await.map_err(openai_multipart_error)? == "word";
+      },
+      _ => {},
+    }
+  }
+
+  let (file_name, file) = upload.ok_or_else(|| openai_error(StatusCode::BAD_REQUEST, "missing file field".to_string()))?;
+
+  if language != AUTO_LANGUAGE && whisper_rs::get_lang_id(&language).is_none() {
+    return Err(openai_error(StatusCode::BAD_REQUEST, format!("unknown language: {}", language)));
+  }
+
+  if !["json", "verbose_json", "text", "srt", "vtt"].contains(&response_format.as_str()) {
+    return Err(openai_error(StatusCode::BAD_REQUEST, format!("unknown response_format {}, expected json, verbose_json, text, srt or vtt", response_format)));
+  }
+
+  let segments = jobs.transcribe(file_name, language.clone(), file).await
+    .map_err(|e| openai_error(StatusCode::INTERNAL_SERVER_ERROR, format!("error transcribing file: {}", e)))?;
+
+  let text = join_segment_texts(segments.iter().map(|segment| segment.text.trim()));
+  // the first detected one for auto
+  let language = segments.iter().find_map(|segment| segment.language.clone()).unwrap_or(language);
+  let lang_id = whisper_rs::get_lang_id(&language);
+
+  let response = match response_format.as_str() {
+    "json" => Json(json!({"text": text})).into_response(),
+    "verbose_json" => {
+      let openai_segments: Vec<serde_json::Value> = segments.iter().enumerate().map(|(i, segment)| json!({
+        "id": i,
+        "seek": 0,
+        "start": segment.start_ms as f64 / 1000.0,
+        "end": segment.end_ms as f64 / 1000.0,
+        "text": segment.text.trim(),
+        "avg_logprob": segment.confidence.avg_logprob,
+        "no_speech_prob": segment.confidence.no_speech_prob,
+      })).collect();
+      let mut body = json!({
+        "task": "transcribe",
+        "language": lang_id.and_then(whisper_rs::get_lang_str_full).unwrap_or(language.as_str()),
+        // up to the end of the last speech, trailing silence is not counted
+        "duration": segments.last().map(|segment| segment.end_ms as f64 / 1000.0).unwrap_or(0.0),
+        "text": text,
+        "segments": openai_segments,
+      });
+      if word_timestamps {
+        body["words"] = segments.iter().flat_map(|segment| segment.words.iter()).map(|word| json!({
+          "word": word.word,
+          "start": word.start_ms as f64 / 1000.0,
+          "end": word.end_ms as f64 / 1000.0,
+        })).collect();
+      }
+      Json(body).into_response()
+    },
+    "text" => ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response(),
+    "srt" => ([(header::CONTENT_TYPE, "application/x-subrip; charset=utf-8")], to_srt(&segments)).into_response(),
+    _ => ([(header::CONTENT_TYPE, "text/vtt; charset=utf-8")], to_vtt(&segments)).into_response(),
+  };
+  Ok(response)
+}
+
 #[derive(Serialize, Deserialize)]
 struct TestResponse {
   message: String,
@@ -310,32 +1103,116 @@ struct TestResponse {
 #[derive(Serialize, Deserialize)]
 struct SessionIdInput {
   id: String,
+  // format of the raw pcm the session will send, 16 kHz mono s16le when not set
+  sample_rate: Option<u32>,
+  channels: Option<u16>,
+  sample_encoding: Option<String>,
 }
 
 #[derive(Clone)]
 struct AppState {
-  tx: Sender::<Option<Segment>>,
+  // only set when receiving audio from the web page, None when just serving transcripts
+  tx: Option<Sender::<Option<Segment>>>,
   pool: Pool<Postgres>,
   session_id: std::sync::Arc<tokio::sync::Mutex<Option<String>>>,
+  jobs: Option<Arc<JobManager>>,
+  ingests: Option<Arc<IngestManager>>,
+  session_format: Arc<tokio::sync::Mutex<PcmFormat>>,
+  audio_archive: Option<Arc<AudioArchive>>,
+  // decoder of the current session when it sends compressed audio
+  decoder: Arc<tokio::sync::Mutex<Option<(String, CompressedAudioDecoder)>>>,
+  // resampler of the current session when it sends pcm at another rate, with its latest timestamp
+  resampler: Arc<tokio::sync::Mutex<Option<(String, PcmResampler, i64)>>>,
+  // default language for uploaded files
+  language: String,
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+  (
+    [(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"))],
+    gather_metrics(),
+  )
+}
+
+async fn healthz() -> impl IntoResponse {
+  let health = check_health();
+  let status = if health.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+  (status, Json(health))
+}
+
+/// Bind before starting anything else, so a port in use is an error instead of a panic in
+/// the server thread.
+pub fn bind_listener(addr: SocketAddr) -> Result<std::net::TcpListener, Box<dyn std::error::Error>> {
+  let listener = std::net::TcpListener::bind(addr).map_err(|e| format!("failed to listen on {}: {}", addr, e))?;
+  // tokio needs it non blocking
+  listener.set_nonblocking(true)?;
+  Ok(listener)
+}
+
+/// Serve only `/metrics` and `/healthz`, for sources that don't run the web server.
+pub async fn start_metrics_server(listener: std::net::TcpListener) {
+  let app = Router::new()
+  .route("/metrics", get(metrics_handler))
+  .route("/healthz", get(healthz));
+  let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+  eprintln!("metrics listening on {}", listener.local_addr().unwrap());
+  axum::serve(listener, app)
+  .await
+  .unwrap();
 }
 
 pub struct TranscribeWebServer {
-  port: u16,
+  addr: SocketAddr,
+  // bound by the caller, otherwise addr is bound when the server starts
+  listener: Option<std::net::TcpListener>,
   state: AppState,
 }
 
 impl TranscribeWebServer {
-  pub fn new(port: u16, tx: Sender::<Option<Segment>>, pool: Pool<Postgres>) -> Self {
+  pub fn new(addr: SocketAddr, tx: Option<Sender::<Option<Segment>>>, pool: Pool<Postgres>) -> Self {
     Self {
-      port,
+      addr,
+      listener: None,
       state: AppState {
         tx,
         session_id: std::sync::Arc::new(tokio::sync::Mutex::new(None)),
         pool,
+        jobs: None,
+        ingests: None,
+        session_format: Arc::new(tokio::sync::Mutex::new(PcmFormat::default())),
+        audio_archive: None,
+        decoder: Arc::new(tokio::sync::Mutex::new(None)),
+        resampler: Arc::new(tokio::sync::Mutex::new(None)),
+        language: "en".to_string(),
       }
     }
   }
   
+  /// Serve on a listener from `bind_listener` instead of binding the address.
+  pub fn with_listener(mut self, listener: std::net::TcpListener) -> Self {
+    self.listener = Some(listener);
+    self
+  }
+  
+  /// Start, pause, resume and stop url ingests under `/api/ingests`.
+  pub fn with_ingests(mut self, ingests: Arc<IngestManager>) -> Self {
+    self.state.ingests = Some(ingests);
+    self
+  }
+  
+  /// Play back transcripts from the speech clips saved in `audio_archive_dir`.
+  pub fn with_audio_archive(mut self, audio_archive_dir: Option<String>) -> Self {
+    self.state.audio_archive = audio_archive_dir.map(|dir| Arc::new(AudioArchive::new(dir)));
+    self
+  }
+  
+  /// Accept uploaded files for transcription under `/api/jobs`, in `language` unless the upload says otherwise.
+  pub fn with_jobs(mut self, jobs: Arc<JobManager>, language: String) -> Self {
+    self.state.jobs = Some(jobs);
+    self.state.language = language;
+    self
+  }
+  
   pub async fn start_webserver(self) {
     
     //let m = move || async move { self.test_api() };
@@ -350,10 +1227,31 @@ impl TranscribeWebServer {
     .route("/api/test", axum::routing::get(test_api))
     .route("/api/get_transcripts", axum::routing::get(get_transcripts))
     .route("/api/get_show_names", axum::routing::get(get_show_names))
+    .route("/api/shows", axum::routing::get(get_shows))
+    .route("/api/search", axum::routing::get(search))
+    .route("/api/transcripts", axum::routing::get(get_transcripts_by_time))
+    .route("/api/transcripts/:id", axum::routing::put(correct_transcript_handler))
+    .route("/api/transcripts/:id/audio", axum::routing::get(get_transcript_audio_clip))
+    .route("/api/transcripts/:id/revisions", axum::routing::get(get_revisions))
+    .route("/api/corrections", axum::routing::get(get_corrections))
     
     .route("/api/set_session_id", axum::routing::post(set_session_id))
     .route("/api/audio_input", axum::routing::post(audio_input))
     
+    .route("/api/jobs", axum::routing::post(create_job).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)))
+    .route("/api/jobs/:id", axum::routing::get(get_job))
+    .route("/api/jobs/:id/result", axum::routing::get(get_job_result))
+    .route("/v1/audio/transcriptions", axum::routing::post(openai_transcriptions).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)))
+    
+    .route("/api/ingests", axum::routing::get(list_ingests).post(start_ingest))
+    .route("/api/ingests/:show_name", axum::routing::get(get_ingest))
+    .route("/api/ingests/:show_name/pause", axum::routing::post(pause_ingest))
+    .route("/api/ingests/:show_name/resume", axum::routing::post(resume_ingest))
+    .route("/api/ingests/:show_name/stop", axum::routing::post(stop_ingest))
+    
+    .route("/metrics", get(metrics_handler))
+    .route("/healthz", get(healthz))
+    
     .with_state(self.state.clone())
     .fallback_service(get(not_found));
     
@@ -448,12 +1346,98 @@ impl TranscribeWebServer {
   // }
   
   async fn serve(self, app: Router) {
-    let addr = SocketAddr::from(([127, 0, 0, 1], self.port));
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let listener = match self.listener {
+      Some(listener) => tokio::net::TcpListener::from_std(listener).unwrap(),
+      None => tokio::net::TcpListener::bind(self.addr).await.unwrap(),
+    };
     eprintln!("listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app.layer(TraceLayer::new_for_http()))
     .await
     .unwrap();
   }
   
-}
\ No newline at end of file
+}
+/// Serve the embedded frontend and the read apis over an existing transcripts database,
+/// without ingesting any audio.
+///
+/// Uploaded files are transcribed with `jobs` and url ingests are controlled with `ingests` when they are provided.
+pub fn serve_transcripts(config: &Config, addr: SocketAddr, jobs: Option<JobManager>, ingests: Option<IngestManager>) -> Result<(), Box<dyn std::error::Error>> {
+  let rt = get_runtime();
+  let database_config = config.database_config.as_ref().ok_or("database_config is required to serve transcripts")?;
+  let pool = init_db_from_config(rt, database_config)?;
+  let mut server = TranscribeWebServer::new(addr, None, pool)
+    .with_listener(bind_listener(addr)?)
+    .with_audio_archive(config.audio_archive_dir.clone());
+  if let Some(jobs) = jobs {
+    server = server.with_jobs(Arc::new(jobs), config.language.clone());
+  }
+  if let Some(ingests) = ingests {
+    server = server.with_ingests(Arc::new(ingests));
+  }
+  rt.block_on(async {
+    server.start_webserver().await
+  });
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn byte_range_start_and_end() {
+    assert_eq!(parse_byte_range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+    assert_eq!(parse_byte_range(" bytes=10-19 ", 100), ByteRange::Partial(10, 19));
+    assert_eq!(parse_byte_range("bytes=90-", 100), ByteRange::Partial(90, 99));
+    assert_eq!(parse_byte_range("bytes=0-500", 100), ByteRange::Partial(0, 99));
+  }
+
+  #[test]
+  fn byte_range_suffix() {
+    assert_eq!(parse_byte_range("bytes=-10", 100), ByteRange::Partial(90, 99));
+    assert_eq!(parse_byte_range("bytes=-200", 100), ByteRange::Partial(0, 99));
+  }
+
+  #[test]
+  fn byte_range_past_the_end() {
+    assert_eq!(parse_byte_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+    assert_eq!(parse_byte_range("bytes=150-200", 100), ByteRange::Unsatisfiable);
+    assert_eq!(parse_byte_range("bytes=0-9", 0), ByteRange::Unsatisfiable);
+  }
+
+  #[test]
+  fn malformed_byte_range_is_ignored() {
+    assert_eq!(parse_byte_range("bytes=5-2", 100), ByteRange::Full);
+    assert_eq!(parse_byte_range("bytes=0-1,5-6", 100), ByteRange::Full);
+    assert_eq!(parse_byte_range("items=0-1", 100), ByteRange::Full);
+    assert_eq!(parse_byte_range("bytes=a-b", 100), ByteRange::Full);
+    assert_eq!(parse_byte_range("bytes=-0", 100), ByteRange::Full);
+    assert_eq!(parse_byte_range("bytes=-", 100), ByteRange::Full);
+    assert_eq!(parse_byte_range("", 100), ByteRange::Full);
+  }
+
+  #[test]
+  fn cjk_segments_are_joined_without_spaces() {
+    assert_eq!(join_segment_texts(["你好。", "今日天氣好好"].into_iter()), "你好。今日天氣好好");
+    assert_eq!(join_segment_texts(["Hello there.", "", "How are you?"].into_iter()), "Hello there. How are you?");
+    assert_eq!(join_segment_texts(["我哋用", "AI", "做字幕"].into_iter()), "我哋用 AI 做字幕");
+  }
+
+  #[test]
+  fn range_responses() {
+    let body = (0..100).collect::<Vec<u8>>();
+    let with_range = |range: &str| {
+      let mut headers = axum::http::HeaderMap::new();
+      headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+      ranged_response(&headers, body.clone(), "audio/ogg")
+    };
+    assert_eq!(with_range("bytes=0-9").status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(with_range("bytes=5-2").status(), StatusCode::OK);
+    assert_eq!(with_range("items=0-1").status(), StatusCode::OK);
+    assert_eq!(with_range("garbage").status(), StatusCode::OK);
+    assert_eq!(with_range("bytes=0-1,5-6").status(), StatusCode::OK);
+    let past_the_end = with_range("bytes=200-");
+    assert_eq!(past_the_end.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(past_the_end.headers()[header::CONTENT_RANGE], "bytes */100");
+  }
+}
@@ -0,0 +1,269 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, error, info, warn};
+use serde_json::{json, Map, Value};
+use whisper_rs::{WhisperContext, WhisperState};
+
+use crate::audio_decoder::{PcmFormat, SampleEncoding};
+use crate::config::Config;
+use crate::prompt::ShowPrompt;
+use crate::decoding::DecodingConfig;
+use crate::postprocess::PostProcessConfig;
+use crate::download_utils::{get_filename_from_url, get_model_download_url};
+use crate::vad_processor::{build_params, load_whisper_context, transcribe_samples, ClipOptions};
+
+pub const DEFAULT_WYOMING_PORT: u16 = 10300;
+
+// a single voice command, not a recording
+const MAX_AUDIO_BYTES: usize = 64 * 1024 * 1024;
+// event data is a small json object, e.g. the audio format or language
+const MAX_DATA_BYTES: usize = 1024 * 1024;
+
+/// One wyoming event: a json header line, followed by optional json data and a binary payload.
+struct Event {
+    event_type: String,
+    data: Map<String, Value>,
+    payload: Vec<u8>,
+}
+
+fn read_event<R: BufRead>(reader: &mut R) -> Result<Option<Event>, Box<dyn std::error::Error>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let header: Value = serde_json::from_str(&line)?;
+    let event_type = header.get("type").and_then(Value::as_str).ok_or("event without type")?.to_string();
+
+    // older clients send data inline in the header
+    let mut data = header.get("data").and_then(Value::as_object).cloned().unwrap_or_default();
+    if let Some(data_length) = header.get("data_length").and_then(Value::as_u64) {
+        if data_length as usize > MAX_DATA_BYTES {
+            return Err(format!("data of {} bytes is too large", data_length).into());
+        }
+        let mut buf = vec![0u8; data_length as usize];
+        reader.read_exact(&mut buf)?;
+        if let Value::Object(extra) = serde_json::from_slice(&buf)? {
+            data.extend(extra);
+        }
+    }
+
+    let mut payload = Vec::new();
+    if let Some(payload_length) = header.get("payload_length").and_then(Value::as_u64) {
+        if payload_length as usize > MAX_AUDIO_BYTES {
+            return Err(format!("payload of {} bytes is too large", payload_length).into());
+        }
+        payload.resize(payload_length as usize, 0);
+        reader.read_exact(&mut payload)?;
+    }
+
+    Ok(Some(Event { event_type, data, payload }))
+}
+
+fn write_event<W: Write>(writer: &mut W, event_type: &str, data: Value) -> Result<(), Box<dyn std::error::Error>> {
+    let data = serde_json::to_vec(&data)?;
+    let header = json!({
+        "type": event_type,
+        "version": "1.5.2",
+        "data_length": data.len(),
+    });
+    writer.write_all(serde_json::to_string(&header)?.as_bytes())?;
+    writer.write_all(b"\n")?;
+    writer.write_all(&data)?;
+    writer.flush()?;
+    Ok(())
+}
+
+struct WyomingServer {
+    model: String,
+    language: String,
+    n_threads: usize,
+    decoding: DecodingConfig,
+    postprocess: PostProcessConfig,
+    ctx: WhisperContext,
+    // one transcription at a time across connections
+    state: Mutex<WhisperState>,
+    prompt: Mutex<ShowPrompt>,
+}
+
+impl WyomingServer {
+    fn info(&self) -> Value {
+        let attribution = json!({
+            "name": "whisper_transcribe_rs",
+            "url": "https://github.com/ggerganov/whisper.cpp",
+        });
+        json!({
+            "asr": [{
+                "name": "whisper_transcribe_rs",
+                "description": "whisper.cpp with silero vad",
+                "attribution": attribution,
+                "installed": true,
+                "version": env!("CARGO_PKG_VERSION"),
+                "models": [{
+                    "name": self.model,
+                    "description": self.model,
+                    "attribution": attribution,
+                    "installed": true,
+                    "languages": [self.language],
+                    "version": null,
+                }],
+            }],
+            "tts": [],
+            "handle": [],
+            "intent": [],
+            "wake": [],
+        })
+    }
+
+    fn transcribe(&self, language: &str, samples: &[i16]) -> Result<String, Box<dyn std::error::Error>> {
+        let mut params = build_params(language, self.n_threads, &self.decoding);
+        let prompt = match self.prompt.lock() {
+            Ok(mut prompt) => prompt.current(),
+            Err(poisoned) => poisoned.into_inner().current(),
+        };
+        if let Some(prompt) = prompt {
+            params.set_initial_prompt(&prompt);
+        }
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => {
+                // the state may be left in a bad state after a panic
+                let mut state = poisoned.into_inner();
+                *state = self.ctx.create_state()?;
+                self.state.clear_poison();
+                state
+            },
+        };
+        let segments = transcribe_samples(&self.ctx, &mut state, &params, samples, ClipOptions { n_threads: self.n_threads, ..ClipOptions::default() }, |_| {})?;
+        let text = segments.iter().map(|segment| segment.text.trim()).collect::<Vec<_>>().join(" ");
+        Ok(self.postprocess.process(language, &text))
+    }
+}
+
+fn audio_format(data: &Map<String, Value>) -> Result<PcmFormat, Box<dyn std::error::Error>> {
+    let rate = data.get("rate").and_then(Value::as_u64).ok_or("audio event without rate")?;
+    let width = data.get("width").and_then(Value::as_u64).ok_or("audio event without width")?;
+    let channels = data.get("channels").and_then(Value::as_u64).ok_or("audio event without channels")?;
+    if width != 2 {
+        return Err(format!("unsupported sample width {}, only 16 bit audio is accepted", width).into());
+    }
+    PcmFormat::new(rate as u32, channels as u16, SampleEncoding::S16le)
+}
+
+fn handle_connection(server: &WyomingServer, stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let mut language = server.language.clone();
+    let mut format: Option<PcmFormat> = None;
+    let mut audio: Vec<u8> = Vec::new();
+
+    while let Some(event) = read_event(&mut reader)? {
+        debug!("wyoming event {}", event.event_type);
+        match event.event_type.as_str() {
+            "describe" => {
+                write_event(&mut writer, "info", server.info())?;
+            },
+            "transcribe" => {
+                // the language of the next audio only
+                language = match event.data.get("language").and_then(Value::as_str) {
+                    Some(requested) if whisper_rs::get_lang_id(requested).is_some() => requested.to_string(),
+                    Some(requested) => {
+                        warn!("unknown language {}, using {}", requested, server.language);
+                        server.language.clone()
+                    },
+                    None => server.language.clone(),
+                };
+            },
+            "audio-start" => {
+                format = Some(audio_format(&event.data)?);
+                audio.clear();
+            },
+            "audio-chunk" => {
+                let chunk_format = audio_format(&event.data)?;
+                if format.map(|format| format != chunk_format).unwrap_or(false) {
+                    return Err("audio format changed within one audio stream".into());
+                }
+                format = Some(chunk_format);
+                if audio.len() + event.payload.len() > MAX_AUDIO_BYTES {
+                    return Err("audio stream is too long".into());
+                }
+                audio.extend_from_slice(&event.payload);
+            },
+            "audio-stop" => {
+                let text = match format {
+                    Some(format) => {
+                        let whole_frames = audio.len() - audio.len() % format.bytes_per_frame();
+                        let samples = format.to_target_samples(&audio[..whole_frames])?;
+                        server.transcribe(&language, &samples)?
+                    },
+                    None => String::new(),
+                };
+                info!("wyoming transcript: {}", text);
+                write_event(&mut writer, "transcript", json!({ "text": text, "language": language }))?;
+                format = None;
+                audio.clear();
+                language = server.language.clone();
+            },
+            other => {
+                debug!("ignoring wyoming event {}", other);
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Serve speech to text over the wyoming protocol for home assistant voice pipelines,
+/// with the host, port, model and language from the `[wyoming]` section of the config.
+pub fn serve_wyoming(config: &Config, n_threads: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let wyoming_config = config.wyoming.as_ref();
+    let host = wyoming_config.and_then(|c| c.host.clone()).unwrap_or_else(|| "0.0.0.0".to_string());
+    let port = wyoming_config.and_then(|c| c.port).unwrap_or(DEFAULT_WYOMING_PORT);
+    let language = wyoming_config.and_then(|c| c.language.clone()).unwrap_or_else(|| config.language.clone());
+
+    let model_download_url = get_model_download_url(wyoming_config.and_then(|c| c.model.as_deref()))?;
+    let ctx = load_whisper_context(model_download_url)?;
+    let state = ctx.create_state()?;
+
+    let server = Arc::new(WyomingServer {
+        model: get_filename_from_url(model_download_url)?,
+        language,
+        n_threads,
+        decoding: config.decoding.clone(),
+        postprocess: config.postprocess.clone(),
+        ctx,
+        state: Mutex::new(state),
+        prompt: Mutex::new(ShowPrompt::new(&wyoming_config.and_then(|c| c.prompt.clone()).unwrap_or_default())),
+    });
+
+    let listener = TcpListener::bind((host.as_str(), port))?;
+    info!("wyoming server listening on {}:{}", host, port);
+    eprintln!("wyoming server listening on {}:{}", host, port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to accept wyoming connection: {}", e);
+                continue;
+            },
+        };
+        let server = server.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+            debug!("wyoming connection from {}", peer);
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                handle_connection(&server, stream).map_err(|e| e.to_string())
+            }));
+            match result {
+                Ok(Ok(())) => debug!("wyoming connection from {} closed", peer),
+                Ok(Err(e)) => warn!("wyoming connection from {} failed: {}", peer, e),
+                Err(_) => error!("wyoming connection from {} panicked", peer),
+            }
+        });
+    }
+    Ok(())
+}